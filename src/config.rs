@@ -1,8 +1,12 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::fmt::{Debug, Display};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use log::log_enabled;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Notify, RwLock, RwLockReadGuard};
+use tokio::sync::{Mutex, Notify, RwLock, RwLockReadGuard};
 
 /// As config may be updated by reloads,
 /// it is proxied behind this provider.
@@ -14,6 +18,8 @@ pub struct ConfigProvider {
     config: RwLock<RootConfig>,
     /// Reload notifier.
     reload_notify: Notify,
+    /// Lock held for the duration of a reload, so that concurrent reloads don't interleave.
+    reload_lock: Mutex<()>,
 }
 
 /// Configuration file.
@@ -27,18 +33,150 @@ pub struct RootConfig {
 
     /// Load balancing method. Defaults to [`LoadBalanceMethod::RoundRobin`].
     pub load_balance_method: Option<LoadBalanceMethod>,
+    /// Time, in seconds, all servers must have been marked as dead before players are sent
+    /// to them regardless of their health. Disabled (fallback is immediate) if unset.
+    pub all_dead_grace_secs: Option<u64>,
+    /// Whether to send players to servers regardless of their health when all of them are
+    /// marked as dead. If false, no server is available until one is alive. Defaults to true.
+    pub route_when_all_dead: Option<bool>,
     /// Whether proxy protocol should be used. Defaults to true.
     pub proxy_protocol: Option<bool>,
+    /// Size, in bytes, of the buffers datagrams are received into.
+    /// Larger datagrams are truncated, a warning is logged when a datagram fills a buffer.
+    /// Defaults to [`crate::raknet::DEFAULT_MTU`].
+    pub recv_buffer_size: Option<usize>,
+    /// Minimum MTU players can request, connections requesting less are rejected. Unchecked if unset.
+    pub min_mtu: Option<u16>,
+    /// Maximum MTU players can request, connection requests asking for more are dropped so that
+    /// players retry with a smaller one. Unchecked if unset.
+    pub max_mtu: Option<u16>,
+    /// Raknet protocol versions players can connect with. Connections with other versions
+    /// are answered with an incompatible protocol version message. Unchecked if unset.
+    pub supported_protocols: Option<Vec<u8>>,
+    /// Whether to answer connection attempts with a no free incoming connection message
+    /// when no server is available, rather than dropping them silently. Defaults to true.
+    pub reply_when_unavailable: Option<bool>,
+    /// Size, in bytes, of the OS receive buffer (`SO_RCVBUF`) of UDP sockets. OS default if unset.
+    pub udp_recv_buffer_bytes: Option<usize>,
+    /// Size, in bytes, of the OS send buffer (`SO_SNDBUF`) of UDP sockets. OS default if unset.
+    pub udp_send_buffer_bytes: Option<usize>,
+    /// Whether to set `SO_REUSEADDR` on UDP sockets. Defaults to false.
+    pub udp_reuse_address: Option<bool>,
+    /// IP ToS/DSCP byte to mark traffic to backend servers with. Unmarked if unset.
+    pub udp_tos: Option<u8>,
+    /// Number of Proxy <-> Server sockets to bind ahead of time and recycle across players.
+    /// Changes are not applied on reload. Disabled if unset.
+    pub socket_pool_size: Option<usize>,
+    /// Time, in seconds, without data from the server after which a client is closed.
+    /// Defaults to 10.
+    pub client_timeout_secs: Option<u64>,
+    /// Time, in seconds, without data from the player after which a client is closed.
+    /// Disabled if unset.
+    pub player_timeout_secs: Option<u64>,
+    /// Time, in seconds, after which a client whose handshake was not accepted by the server
+    /// is closed as rejected. Disabled if unset.
+    pub handshake_timeout_secs: Option<u64>,
+    /// Interval, in seconds, at which to ping the server of each client to detect unresponsive
+    /// servers on idle sessions. Disabled if unset.
+    pub keepalive_interval_secs: Option<u64>,
+    /// Maximum number of new connections per second from a single IP. Unlimited if unset.
+    pub connection_rate_limit: Option<u32>,
+    /// Maximum number of unconnected pings per second from a single IP. Unlimited if unset.
+    pub ping_rate_limit: Option<u32>,
+    /// Maximum number of clients in the Raknet handshake at the same time, including the ones
+    /// being set up (socket bind, server pick). Handshakes from new addresses past that are dropped,
+    /// connected players and retries from clients already in the handshake are not affected.
+    /// Unlimited if unset.
+    pub max_concurrent_handshakes: Option<usize>,
+    /// Whether to close connections that seem lost, i.e. the server keeps retransmitting
+    /// data the player never acknowledges. Defaults to false.
+    pub detect_connection_lost: Option<bool>,
+    /// Where to publish connection and health events. Disabled if unset.
+    /// Changes are not applied on reload.
+    pub events: Option<EventsConfig>,
     /// Backend to route players to.
     pub backend: BackendConfig,
 }
 
+/// Configuration for publishing proxy events to a message broker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventsConfig {
+    /// URL of the NATS server to publish events to.
+    pub nats_url: String,
+    /// Subject to publish events on.
+    pub subject: String,
+}
+
 /// Load balancing method.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum LoadBalanceMethod {
     RoundRobin,
     LeastConnected,
+    WeightedLeastConnected,
+}
+
+/// How backend servers are probed for health checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    /// Raknet unconnected ping.
+    Raknet,
+    /// Open and close a TCP connection to the server address.
+    Tcp,
+}
+
+/// Transport protocol advertised in the HAProxy PROXY protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocolMode {
+    /// Datagram (UDP).
+    Udp,
+    /// Stream (TCP).
+    Tcp,
+}
+
+/// Version of the HAProxy PROXY protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum ProxyProtocolVersion {
+    /// Human-readable header.
+    V1,
+    /// Binary header.
+    V2,
+}
+
+impl TryFrom<u8> for ProxyProtocolVersion {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            _ => Err(format!("unsupported PROXY protocol version {}", value)),
+        }
+    }
+}
+
+impl From<ProxyProtocolVersion> for u8 {
+    fn from(value: ProxyProtocolVersion) -> Self {
+        match value {
+            ProxyProtocolVersion::V1 => 1,
+            ProxyProtocolVersion::V2 => 2,
+        }
+    }
+}
+
+/// Source of the player count advertised in the MOTD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayerCountSource {
+    /// Player count reflected from the backend MOTD.
+    Backend,
+    /// Number of players connected through the proxy.
+    Proxy,
+    /// Sum of both.
+    Sum,
 }
 
 /// Configuration for a backend.
@@ -46,19 +184,95 @@ pub enum LoadBalanceMethod {
 pub struct BackendConfig {
     /// Rate, in seconds, at which to ping servers to check health.
     pub health_check_rate: u64,
+    /// How to check the health of servers. Defaults to [`HealthCheckKind::Raknet`].
+    pub health_check: Option<HealthCheckKind>,
+    /// Whether Raknet health checks of all servers share a single UDP socket,
+    /// instead of binding one per ping. Defaults to `false`.
+    pub health_check_shared_socket: Option<bool>,
+    /// Maximum number of servers being health checked at once. Unlimited if unset.
+    pub health_check_concurrency: Option<usize>,
+    /// Whether to spread health checks of servers over the check interval,
+    /// rather than checking them all at once. Not applied when `health_check_shared_socket`
+    /// is enabled. Defaults to `false`.
+    pub health_check_jitter: Option<bool>,
+    /// URL to POST a JSON payload to when the health of a server changes.
+    pub health_webhook_url: Option<String>,
     /// Rate, in seconds, at which to fetch MOTD information.
     pub motd_refresh_rate: u64,
+    /// Addresses of the servers to ping to get MOTD information.
+    /// Defaults to the backend servers.
+    pub motd_sources: Option<Vec<String>>,
     /// Address of the server to ping to get MOTD information.
+    ///
+    /// Deprecated, superseded by `motd_sources`.
     pub motd_source: Option<String>,
+    /// First MOTD line to advertise instead of the reflected one.
+    pub motd_line1: Option<String>,
+    /// Second MOTD line to advertise instead of the reflected one.
+    pub motd_line2: Option<String>,
+    /// First MOTD line to advertise when the title ends up empty, as clients ignore
+    /// replies with an empty title. Defaults to `...`.
+    pub motd_empty_title: Option<String>,
+    /// Version name to advertise instead of the reflected one.
+    pub version_name: Option<String>,
+    /// Where the advertised player count comes from. Defaults to [`PlayerCountSource::Backend`].
+    pub player_count_source: Option<PlayerCountSource>,
     /// Servers to proxy players to.
     pub servers: Vec<BackendServerConfig>,
 }
 
+impl BackendConfig {
+    /// Gets the addresses of the servers to fetch MOTD information from.
+    ///
+    /// Falls back to the backend servers when no MOTD source is configured.
+    pub fn motd_sources_or_default(&self) -> Vec<String> {
+        match (&self.motd_sources, &self.motd_source) {
+            (Some(sources), _) if !sources.is_empty() => sources.clone(),
+            (_, Some(source)) => vec![source.clone()],
+            _ => self
+                .servers
+                .iter()
+                .map(|server| server.address.clone())
+                .collect(),
+        }
+    }
+}
+
 /// Configuration for a backend server.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BackendServerConfig {
     /// Address of the server.
     pub address: String,
+    /// IP ToS/DSCP byte to mark traffic to this server with, instead of `udp_tos`.
+    pub tos: Option<u8>,
+    /// Transport protocol to advertise in the PROXY protocol header.
+    /// Defaults to [`ProxyProtocolMode::Udp`].
+    pub proxy_protocol_mode: Option<ProxyProtocolMode>,
+    /// Version of the PROXY protocol header. Defaults to [`ProxyProtocolVersion::V2`].
+    pub proxy_protocol_version: Option<ProxyProtocolVersion>,
+    /// Relative capacity of the server, for weighted load balancing. Defaults to 1.
+    pub weight: Option<u32>,
+}
+
+/// An invalid value in the configuration.
+#[derive(Debug, Clone)]
+pub enum ConfigError {
+    /// An address can't be parsed as a socket address.
+    InvalidAddress {
+        field: &'static str,
+        address: String,
+    },
+    /// There are no backend servers.
+    NoBackendServers,
+    /// A backend server address is configured more than once.
+    DuplicateServer(SocketAddr),
+    /// A value that must be positive is zero.
+    ZeroValue(&'static str),
+    /// The lower bound of a range is greater than its upper bound.
+    InvertedRange {
+        min: &'static str,
+        max: &'static str,
+    },
 }
 
 impl ConfigProvider {
@@ -67,12 +281,16 @@ impl ConfigProvider {
             config_file,
             config: RwLock::new(config),
             reload_notify: Notify::new(),
+            reload_lock: Mutex::new(()),
         }
     }
 }
 
 /// Attempts to read the configuration file.
 ///
+/// The format (TOML, YAML or JSON) is picked from the file extension.
+/// Environment variable overrides are applied to the parsed config.
+///
 /// ## Arguments
 ///
 /// * `config_file` - Config file path
@@ -82,11 +300,14 @@ impl ConfigProvider {
 /// A [`ConfigProvider`] that is guaranteed to have the config already loaded and without errors.
 pub fn read_config(config_file: PathBuf) -> anyhow::Result<ConfigProvider> {
     let contents = std::fs::read_to_string(&config_file)?;
-    let config: RootConfig = toml::from_str(&contents)?;
+    let mut config = parse_config(&config_file, &contents)?;
+    config.apply_env_overrides();
+    config.validate().map_err(validation_error)?;
     let config_provider = ConfigProvider {
         config_file,
         config: RwLock::new(config),
         reload_notify: Notify::new(),
+        reload_lock: Mutex::new(()),
     };
     Ok(config_provider)
 }
@@ -104,6 +325,7 @@ impl ConfigProvider {
 
     /// Reloads the configuration.
     pub async fn reload(&self) {
+        let _guard = self.reload_lock.lock().await;
         let config = match self.read_config().await {
             Ok(config) => config,
             Err(err) => {
@@ -112,9 +334,15 @@ impl ConfigProvider {
             }
         };
         let mut w = self.config.write().await;
+        let proxy_protocol_changed =
+            w.proxy_protocol.unwrap_or(true) != config.proxy_protocol.unwrap_or(true);
         *w = config;
         drop(w);
         log::info!("Config file reloaded.");
+        if proxy_protocol_changed {
+            // the proxy protocol header is only sent once when a client is created
+            log::info!("Proxy protocol setting changed, it will only apply to new connections.");
+        }
         if log_enabled!(log::Level::Debug) {
             let config = self.read().await;
             log::debug!("Parsed configuration: {:#?}", config);
@@ -124,7 +352,297 @@ impl ConfigProvider {
 
     async fn read_config(&self) -> anyhow::Result<RootConfig> {
         let contents = tokio::fs::read_to_string(&self.config_file).await?;
-        let config: RootConfig = toml::from_str(&contents)?;
+        let mut config = parse_config(&self.config_file, &contents)?;
+        config.apply_env_overrides();
+        config.validate().map_err(validation_error)?;
         Ok(config)
     }
 }
+
+impl RootConfig {
+    /// Gets the IP ToS/DSCP byte to mark traffic to a server with, if any.
+    ///
+    /// ## Arguments
+    ///
+    /// * `addr` - Server address
+    pub fn tos_for(&self, addr: SocketAddr) -> Option<u8> {
+        self.server_config(addr)
+            .and_then(|server| server.tos)
+            .or(self.udp_tos)
+    }
+
+    /// Gets the transport protocol to advertise in the PROXY protocol header sent to a server.
+    ///
+    /// ## Arguments
+    ///
+    /// * `addr` - Address of the backend server
+    pub fn proxy_protocol_mode_for(&self, addr: SocketAddr) -> ProxyProtocolMode {
+        self.server_config(addr)
+            .and_then(|server| server.proxy_protocol_mode)
+            .unwrap_or(ProxyProtocolMode::Udp)
+    }
+
+    /// Gets the version of the PROXY protocol header to send to a server.
+    ///
+    /// ## Arguments
+    ///
+    /// * `addr` - Address of the backend server
+    pub fn proxy_protocol_version_for(&self, addr: SocketAddr) -> ProxyProtocolVersion {
+        self.server_config(addr)
+            .and_then(|server| server.proxy_protocol_version)
+            .unwrap_or(ProxyProtocolVersion::V2)
+    }
+
+    /// Finds the configuration of a backend server.
+    fn server_config(&self, addr: SocketAddr) -> Option<&BackendServerConfig> {
+        self.backend
+            .servers
+            .iter()
+            .find(|server| SocketAddr::from_str(&server.address) == Ok(addr))
+    }
+
+    /// Checks the configuration for invalid values.
+    ///
+    /// ## Returns
+    ///
+    /// Every error found, if any.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let mut check_address = |field, address: &str| {
+            let addr = SocketAddr::from_str(address);
+            if addr.is_err() {
+                errors.push(ConfigError::InvalidAddress {
+                    field,
+                    address: address.to_owned(),
+                });
+            }
+            addr.ok()
+        };
+        check_address("bind_address", &self.bind_address);
+        check_address("proxy_bind", &self.proxy_bind);
+        let mut seen = HashSet::new();
+        let mut duplicates = Vec::new();
+        for server in self.backend.servers.iter() {
+            if let Some(addr) = check_address("backend.servers.address", &server.address) {
+                if !seen.insert(addr) {
+                    duplicates.push(addr);
+                }
+            }
+        }
+        errors.extend(duplicates.into_iter().map(ConfigError::DuplicateServer));
+        for server in self.backend.servers.iter() {
+            if server.weight == Some(0) {
+                errors.push(ConfigError::ZeroValue("backend.servers.weight"));
+            }
+        }
+        if self.backend.servers.is_empty() {
+            errors.push(ConfigError::NoBackendServers);
+        }
+        if self.backend.health_check_rate == 0 {
+            errors.push(ConfigError::ZeroValue("backend.health_check_rate"));
+        }
+        if self.backend.health_check_concurrency == Some(0) {
+            errors.push(ConfigError::ZeroValue("backend.health_check_concurrency"));
+        }
+        if self.backend.motd_refresh_rate == 0 {
+            errors.push(ConfigError::ZeroValue("backend.motd_refresh_rate"));
+        }
+        if self.client_timeout_secs == Some(0) {
+            errors.push(ConfigError::ZeroValue("client_timeout_secs"));
+        }
+        if self.player_timeout_secs == Some(0) {
+            errors.push(ConfigError::ZeroValue("player_timeout_secs"));
+        }
+        if self.handshake_timeout_secs == Some(0) {
+            errors.push(ConfigError::ZeroValue("handshake_timeout_secs"));
+        }
+        if self.keepalive_interval_secs == Some(0) {
+            errors.push(ConfigError::ZeroValue("keepalive_interval_secs"));
+        }
+        if matches!((self.min_mtu, self.max_mtu), (Some(min), Some(max)) if min > max) {
+            errors.push(ConfigError::InvertedRange {
+                min: "min_mtu",
+                max: "max_mtu",
+            });
+        }
+        if self.connection_rate_limit == Some(0) {
+            errors.push(ConfigError::ZeroValue("connection_rate_limit"));
+        }
+        if self.ping_rate_limit == Some(0) {
+            errors.push(ConfigError::ZeroValue("ping_rate_limit"));
+        }
+        if self.recv_buffer_size == Some(0) {
+            errors.push(ConfigError::ZeroValue("recv_buffer_size"));
+        }
+        if self.max_concurrent_handshakes == Some(0) {
+            errors.push(ConfigError::ZeroValue("max_concurrent_handshakes"));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Overrides config values with the matching `TRAKT_*` environment variables, if set.
+    ///
+    /// Values that can't be parsed are ignored, keeping the value from the config file.
+    pub fn apply_env_overrides(&mut self) {
+        if let Some(bind_address) = env_override("TRAKT_BIND_ADDRESS") {
+            self.bind_address = bind_address;
+        }
+        if let Some(proxy_bind) = env_override("TRAKT_PROXY_BIND") {
+            self.proxy_bind = proxy_bind;
+        }
+        if let Some(rate) = env_override("TRAKT_HEALTH_CHECK_RATE") {
+            self.backend.health_check_rate = rate;
+        }
+        if let Some(rate) = env_override("TRAKT_MOTD_REFRESH_RATE") {
+            self.backend.motd_refresh_rate = rate;
+        }
+        if let Some(limit) = env_override("TRAKT_CONNECTION_RATE_LIMIT") {
+            self.connection_rate_limit = Some(limit);
+        }
+        if let Some(limit) = env_override("TRAKT_PING_RATE_LIMIT") {
+            self.ping_rate_limit = Some(limit);
+        }
+    }
+}
+
+/// Reads and parses a config override from an environment variable.
+///
+/// ## Arguments
+///
+/// * `name` - Name of the environment variable
+fn env_override<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: Debug,
+{
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(err) => {
+            log::warn!(
+                "Ignoring invalid value for environment variable {}: {:?}",
+                name,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Parses configuration contents according to the config file extension.
+///
+/// Files without a known extension are parsed as TOML, the historical format.
+///
+/// ## Arguments
+///
+/// * `config_file` - Config file path
+/// * `contents` - Contents of the config file
+fn parse_config(config_file: &Path, contents: &str) -> anyhow::Result<RootConfig> {
+    let extension = config_file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let config = match extension {
+        "json" => serde_json::from_str(contents)?,
+        #[cfg(feature = "yaml")]
+        "yaml" | "yml" => serde_yaml::from_str(contents)?,
+        #[cfg(not(feature = "yaml"))]
+        "yaml" | "yml" => {
+            return Err(anyhow::anyhow!(
+                "YAML config files require trakt to be built with the `yaml` feature"
+            ))
+        }
+        _ => toml::from_str(contents)?,
+    };
+    Ok(config)
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidAddress { field, address } => {
+                write!(f, "{}: '{}' is not a valid socket address", field, address)
+            }
+            Self::NoBackendServers => write!(f, "backend.servers: no backend server configured"),
+            Self::DuplicateServer(addr) => {
+                write!(f, "backend.servers: {} is configured more than once", addr)
+            }
+            Self::ZeroValue(field) => write!(f, "{}: must be greater than 0", field),
+            Self::InvertedRange { min, max } => {
+                write!(f, "{}: must not be greater than {}", min, max)
+            }
+        }
+    }
+}
+
+/// Summarizes config validation errors into a single error.
+fn validation_error(errors: Vec<ConfigError>) -> anyhow::Error {
+    let summary: String = errors.iter().map(|err| format!("\n  - {}", err)).collect();
+    anyhow::anyhow!("Invalid configuration:{}", summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(extra: &str) -> RootConfig {
+        toml::from_str(&toml_config(extra)).unwrap()
+    }
+
+    fn toml_config(extra: &str) -> String {
+        format!(
+            r#"
+bind_address = "127.0.0.1:19132"
+proxy_bind = "127.0.0.1:0"
+{}
+
+[backend]
+health_check_rate = 60
+motd_refresh_rate = 60
+servers = [{{ address = "127.0.0.1:19133" }}]
+"#,
+            extra
+        )
+    }
+
+    #[test]
+    fn parses_unknown_extensions_as_toml() {
+        let contents = toml_config("");
+        for file in ["config.toml", "config", "trakt.conf", "config.TOML"] {
+            let config = parse_config(Path::new(file), &contents).unwrap();
+            assert_eq!(config.bind_address, "127.0.0.1:19132");
+        }
+        let contents = r#"{
+            "bind_address": "127.0.0.1:19132",
+            "proxy_bind": "127.0.0.1:0",
+            "backend": {
+                "health_check_rate": 60,
+                "motd_refresh_rate": 60,
+                "servers": [{ "address": "127.0.0.1:19133" }]
+            }
+        }"#;
+        let config = parse_config(Path::new("config.json"), contents).unwrap();
+        assert_eq!(config.bind_address, "127.0.0.1:19132");
+        assert!(parse_config(Path::new("config"), contents).is_err());
+    }
+
+    #[test]
+    fn rejects_inverted_mtu_range() {
+        let errors = parse("min_mtu = 1400\nmax_mtu = 1200")
+            .validate()
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            ConfigError::InvertedRange {
+                min: "min_mtu",
+                max: "max_mtu"
+            }
+        ));
+        assert!(parse("min_mtu = 1200\nmax_mtu = 1200").validate().is_ok());
+        assert!(parse("min_mtu = 1500").validate().is_ok());
+    }
+}
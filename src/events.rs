@@ -0,0 +1,179 @@
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{config::EventsConfig, proxy::DisconnectCause};
+
+/// An event happening in the proxy, that external systems may want to react to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProxyEvent {
+    /// A player connected to a backend server.
+    Connect {
+        client_addr: SocketAddr,
+        server_addr: SocketAddr,
+    },
+    /// A player disconnected from a backend server.
+    Disconnect {
+        client_addr: SocketAddr,
+        server_addr: SocketAddr,
+        cause: DisconnectCause,
+        /// Human readable details about the cause, when known.
+        reason: Option<String>,
+    },
+    /// The health status of a backend server changed.
+    HealthChange {
+        server_addr: SocketAddr,
+        alive: bool,
+    },
+}
+
+/// Information about a client of the proxy.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    /// Remote player client address.
+    pub addr: SocketAddr,
+    /// Address of the backend server the player is proxied to.
+    pub server_addr: SocketAddr,
+}
+
+/// Future returned by [`ProxyObserver`] callbacks.
+pub type ObserverFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Callbacks on what happens in the proxy.
+///
+/// Each callback runs in its own task, so a slow observer doesn't hold back
+/// the proxy. Callbacks may therefore complete out of order. All of them do
+/// nothing by default.
+pub trait ProxyObserver: Send + Sync {
+    /// Called when a player connected to a backend server.
+    fn on_connect<'a>(&'a self, _client: &'a ClientInfo) -> ObserverFuture<'a> {
+        Box::pin(async {})
+    }
+
+    /// Called when a player disconnected from a backend server.
+    ///
+    /// `reason` holds human readable details about the cause, when known.
+    fn on_disconnect<'a>(
+        &'a self,
+        _client: &'a ClientInfo,
+        _cause: DisconnectCause,
+        _reason: Option<&'a str>,
+    ) -> ObserverFuture<'a> {
+        Box::pin(async {})
+    }
+
+    /// Called when the health status of a backend server changed.
+    fn on_health_change(&self, _server_addr: SocketAddr, _alive: bool) -> ObserverFuture<'_> {
+        Box::pin(async {})
+    }
+}
+
+/// A destination for [`ProxyEvent`]s.
+///
+/// Every event sink is a [`ProxyObserver`] publishing an event for each callback.
+pub trait EventSink: Send + Sync {
+    /// Publishes an event.
+    fn publish(&self, event: ProxyEvent);
+}
+
+impl<T: EventSink> ProxyObserver for T {
+    fn on_connect<'a>(&'a self, client: &'a ClientInfo) -> ObserverFuture<'a> {
+        self.publish(ProxyEvent::Connect {
+            client_addr: client.addr,
+            server_addr: client.server_addr,
+        });
+        Box::pin(async {})
+    }
+
+    fn on_disconnect<'a>(
+        &'a self,
+        client: &'a ClientInfo,
+        cause: DisconnectCause,
+        reason: Option<&'a str>,
+    ) -> ObserverFuture<'a> {
+        self.publish(ProxyEvent::Disconnect {
+            client_addr: client.addr,
+            server_addr: client.server_addr,
+            cause,
+            reason: reason.map(str::to_owned),
+        });
+        Box::pin(async {})
+    }
+
+    fn on_health_change(&self, server_addr: SocketAddr, alive: bool) -> ObserverFuture<'_> {
+        self.publish(ProxyEvent::HealthChange { server_addr, alive });
+        Box::pin(async {})
+    }
+}
+
+/// Initializes the event sink described by the configuration.
+///
+/// ## Arguments
+///
+/// * `config` - Events configuration
+#[cfg(feature = "nats")]
+pub async fn init_sink(config: &EventsConfig) -> Option<Arc<dyn ProxyObserver>> {
+    match nats::NatsEventSink::connect(&config.nats_url, config.subject.clone()).await {
+        Ok(sink) => {
+            log::info!("Publishing events to NATS server {}", config.nats_url);
+            Some(Arc::new(sink))
+        }
+        Err(err) => {
+            log::error!(
+                "Could not connect to NATS server {}: {:?}",
+                config.nats_url,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Initializes the event sink described by the configuration.
+///
+/// ## Arguments
+///
+/// * `config` - Events configuration
+#[cfg(not(feature = "nats"))]
+pub async fn init_sink(_config: &EventsConfig) -> Option<Arc<dyn ProxyObserver>> {
+    log::warn!("Publishing events requires trakt to be built with the `nats` feature. Ignoring.");
+    None
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+    use super::{EventSink, ProxyEvent};
+
+    /// Publishes events as JSON on a NATS subject.
+    pub struct NatsEventSink {
+        client: async_nats::Client,
+        subject: String,
+    }
+
+    impl NatsEventSink {
+        pub async fn connect(url: &str, subject: String) -> anyhow::Result<Self> {
+            let client = async_nats::connect(url).await?;
+            Ok(Self { client, subject })
+        }
+    }
+
+    impl EventSink for NatsEventSink {
+        fn publish(&self, event: ProxyEvent) {
+            let payload = match serde_json::to_vec(&event) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    log::warn!("Could not serialize event {:?}: {:?}", event, err);
+                    return;
+                }
+            };
+            let client = self.client.clone();
+            let subject = self.subject.clone();
+            tokio::spawn(async move {
+                if let Err(err) = client.publish(subject, payload.into()).await {
+                    log::warn!("Could not publish event to NATS: {:?}", err);
+                }
+            });
+        }
+    }
+}
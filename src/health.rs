@@ -1,27 +1,55 @@
 use std::{
-    sync::{Arc, Weak},
-    time::Duration,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use serde::Serialize;
+
 use tokio::{
+    net::TcpStream,
     sync::{Mutex, Semaphore},
     task::JoinSet,
 };
 
-use crate::{config::ConfigProvider, load_balancer::BackendServer, raknet::ping};
+use crate::{
+    config::{ConfigProvider, HealthCheckKind, ProxyProtocolMode, ProxyProtocolVersion},
+    events::ProxyObserver,
+    load_balancer::BackendServer,
+    raknet::ping::{self, Motd},
+};
+
+/// Time a server has to answer a health check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Controller overseeing the health of all backend servers.
 pub struct HealthController {
     execute_lock: Semaphore,
+    /// Slots of servers being checked at once, with the limit they were created for.
+    check_permits: std::sync::Mutex<Option<(usize, Arc<Semaphore>)>>,
 
     /// Config provider.
     config_provider: Arc<ConfigProvider>,
+    /// Observer notified of health changes.
+    observer: Option<Arc<dyn ProxyObserver>>,
 
     /// Knonwn backend servers. This may include stale servers that are
     /// no longer used by the load balancer.
     servers: Mutex<Vec<Weak<BackendServer>>>,
 }
 
+/// Payload POSTed to the health webhook when the health of a server changes.
+#[derive(Debug, Serialize)]
+struct HealthWebhookPayload {
+    server_addr: SocketAddr,
+    alive: bool,
+    ever_alive: bool,
+    /// UNIX timestamp, in seconds.
+    timestamp: u64,
+}
+
 /// Health information about a backend server.
 #[derive(Debug, Default)]
 pub struct ServerHealth {
@@ -29,15 +57,34 @@ pub struct ServerHealth {
     pub alive: bool,
     /// Whether the server was ever alive.
     pub ever_alive: bool,
+    /// Whether a health check of the server ever completed.
+    pub checked: bool,
     /// Number of failed ping attempts in a row.
     pub failed_attempts: usize,
+    /// MOTD information from the last successful Raknet health check, if any.
+    pub motd: Option<Motd>,
+}
+
+impl ServerHealth {
+    /// Whether players can be sent to the server as far as its health is known.
+    ///
+    /// A server that was never checked isn't known to be dead yet.
+    #[inline]
+    pub fn is_pickable(&self) -> bool {
+        self.alive || !self.checked
+    }
 }
 
 impl HealthController {
-    pub fn new(config_provider: Arc<ConfigProvider>) -> Self {
+    pub fn new(
+        config_provider: Arc<ConfigProvider>,
+        observer: Option<Arc<dyn ProxyObserver>>,
+    ) -> Self {
         Self {
             execute_lock: Semaphore::new(1),
+            check_permits: std::sync::Mutex::new(None),
             config_provider,
+            observer,
             servers: Mutex::new(Vec::new()),
         }
     }
@@ -51,23 +98,88 @@ impl HealthController {
     /// Executes a health check of all servers.
     /// Stale servers that have finished being used will be removed here too.
     pub async fn execute(&self) {
-        let _permit = self.execute_lock.acquire();
-        let (local_addr, proxy_protocol) = {
+        // checks of a round can take longer than the interval, rounds must not overlap
+        // for the concurrency limit to hold, nor pile up waiting for each other
+        let _permit = match self.execute_lock.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::debug!("Previous health checks are still running, skipping this round");
+                return;
+            }
+        };
+        let (local_addr, proxy_protocol, kind, shared_socket, concurrency, jitter, webhook_url) = {
             let config = self.config_provider.read().await;
             let proxy_protocol = config.proxy_protocol.unwrap_or(true);
-            (config.proxy_bind.clone(), proxy_protocol)
+            let kind = config
+                .backend
+                .health_check
+                .unwrap_or(HealthCheckKind::Raknet);
+            let jitter = config.backend.health_check_jitter.unwrap_or(false);
+            let interval = Duration::from_secs(u64::max(config.backend.health_check_rate, 1));
+            (
+                config.proxy_bind.clone(),
+                proxy_protocol,
+                kind,
+                config.backend.health_check_shared_socket.unwrap_or(false),
+                config.backend.health_check_concurrency,
+                jitter.then_some(interval),
+                config.backend.health_webhook_url.clone(),
+            )
         };
+        let permits = self.check_permits(concurrency);
         let mut servers = self.servers.lock().await;
         servers.retain(|server| server.upgrade().is_some());
+        if matches!(kind, HealthCheckKind::Raknet) && shared_socket {
+            let shared_servers = servers.iter().filter_map(Weak::upgrade).collect::<Vec<_>>();
+            drop(servers);
+            self.execute_shared(
+                local_addr,
+                proxy_protocol,
+                shared_servers,
+                concurrency.zip(permits),
+                webhook_url,
+            )
+            .await;
+            return;
+        }
+        // checks past the limit wait for a slot, each check is still bounded by its timeout
         let mut join_set = JoinSet::new();
         for weak_ref in servers.iter() {
             let server = match weak_ref.upgrade() {
                 Some(server) => server,
                 None => continue,
             };
+            let proxy_protocol = if proxy_protocol {
+                let config = self.config_provider.read().await;
+                Some((
+                    config.proxy_protocol_version_for(server.addr),
+                    config.proxy_protocol_mode_for(server.addr),
+                ))
+            } else {
+                None
+            };
             let local_addr = local_addr.clone();
+            let observer = self.observer.clone();
+            let webhook_url = webhook_url.clone();
+            let permits = permits.clone();
+            let offset = jitter.map(|interval| check_offset(server.addr, interval));
             join_set.spawn(async move {
-                HealthController::check_health(local_addr, proxy_protocol, server).await;
+                if let Some(offset) = offset {
+                    tokio::time::sleep(offset).await;
+                }
+                let _permit = match permits {
+                    Some(permits) => permits.acquire_owned().await.ok(),
+                    None => None,
+                };
+                HealthController::check_health(
+                    kind,
+                    local_addr,
+                    proxy_protocol,
+                    server,
+                    observer,
+                    webhook_url,
+                )
+                .await;
             });
         }
         drop(servers);
@@ -79,14 +191,144 @@ impl HealthController {
         }
     }
 
+    /// Gets the slots of servers being checked at once, if limited.
+    ///
+    /// The same slots are kept across rounds, unless the limit changed.
+    ///
+    /// ## Arguments
+    ///
+    /// * `concurrency` - Maximum number of servers being checked at once
+    fn check_permits(&self, concurrency: Option<usize>) -> Option<Arc<Semaphore>> {
+        let mut check_permits = self.check_permits.lock().unwrap();
+        let concurrency = match concurrency {
+            Some(concurrency) => concurrency,
+            None => {
+                *check_permits = None;
+                return None;
+            }
+        };
+        match &*check_permits {
+            Some((limit, permits)) if *limit == concurrency => Some(permits.clone()),
+            _ => {
+                let permits = Arc::new(Semaphore::new(concurrency));
+                *check_permits = Some((concurrency, permits.clone()));
+                Some(permits)
+            }
+        }
+    }
+
+    /// Executes a Raknet health check of servers over a single UDP socket.
+    ///
+    /// ## Arguments
+    ///
+    /// * `local_addr` - Local address to bind the UDP socket to
+    /// * `proxy_protocol` - Whether proxy protocol is required by the servers
+    /// * `servers` - Servers to check
+    /// * `limit` - Maximum number of servers being checked at once, with their slots, if limited
+    /// * `webhook_url` - URL to send health changes to, if any
+    async fn execute_shared(
+        &self,
+        local_addr: String,
+        proxy_protocol: bool,
+        servers: Vec<Arc<BackendServer>>,
+        limit: Option<(usize, Arc<Semaphore>)>,
+        webhook_url: Option<String>,
+    ) {
+        let targets = {
+            let config = self.config_provider.read().await;
+            servers
+                .iter()
+                .map(|server| {
+                    let header = (
+                        config.proxy_protocol_version_for(server.addr),
+                        config.proxy_protocol_mode_for(server.addr),
+                    );
+                    (server.addr, Some(header).filter(|_| proxy_protocol))
+                })
+                .collect::<Vec<_>>()
+        };
+        log::debug!(
+            "Checking health of {} backend servers over a single socket...",
+            servers.len()
+        );
+        // servers past the limit are pinged in later batches
+        let batch_size = match &limit {
+            Some((concurrency, _)) => *concurrency,
+            None => targets.len(),
+        };
+        let mut motds = HashMap::with_capacity(targets.len());
+        for batch in targets.chunks(batch_size.max(1)) {
+            let _permits = match &limit {
+                Some((_, permits)) => permits.acquire_many(batch.len() as u32).await.ok(),
+                None => None,
+            };
+            match ping::ping_many(&local_addr, batch, CHECK_TIMEOUT).await {
+                Ok(batch_motds) => motds.extend(batch_motds),
+                Err(err) => log::warn!("Could not ping backend servers: {:?}", err),
+            }
+        }
+        for server in servers {
+            let motd = motds.remove(&server.addr);
+            HealthController::record_health(
+                motd.is_some(),
+                motd,
+                server,
+                self.observer.clone(),
+                webhook_url.clone(),
+            )
+            .await;
+        }
+    }
+
     /// Performs a health check on server.
-    async fn check_health(local_addr: String, proxy_protocol: bool, server: Arc<BackendServer>) {
-        let timeout = Duration::from_secs(5);
-        let success = ping::ping(&local_addr, &server.addr, proxy_protocol, timeout)
-            .await
-            .is_ok();
+    async fn check_health(
+        kind: HealthCheckKind,
+        local_addr: String,
+        proxy_protocol: Option<(ProxyProtocolVersion, ProxyProtocolMode)>,
+        server: Arc<BackendServer>,
+        observer: Option<Arc<dyn ProxyObserver>>,
+        webhook_url: Option<String>,
+    ) {
+        let (success, motd) = match kind {
+            HealthCheckKind::Raknet => {
+                match ping::ping(&local_addr, &server.addr, proxy_protocol, CHECK_TIMEOUT).await {
+                    Ok(motd) => (true, Some(motd)),
+                    Err(_) => (false, None),
+                }
+            }
+            HealthCheckKind::Tcp => {
+                let success = matches!(
+                    tokio::time::timeout(CHECK_TIMEOUT, TcpStream::connect(server.addr)).await,
+                    Ok(Ok(_))
+                );
+                (success, None)
+            }
+        };
+        HealthController::record_health(success, motd, server, observer, webhook_url).await;
+    }
+
+    /// Updates the health of a server with the result of a check.
+    ///
+    /// ## Arguments
+    ///
+    /// * `success` - Whether the check succeeded
+    /// * `motd` - MOTD information received during the check, if any
+    /// * `server` - Checked server
+    /// * `observer` - Observer to notify of health changes, if any
+    /// * `webhook_url` - URL to send health changes to, if any
+    async fn record_health(
+        success: bool,
+        motd: Option<Motd>,
+        server: Arc<BackendServer>,
+        observer: Option<Arc<dyn ProxyObserver>>,
+        webhook_url: Option<String>,
+    ) {
         let mut health = server.health.write().await;
         let prev_alive = health.alive;
+        health.checked = true;
+        if motd.is_some() {
+            health.motd = motd;
+        }
         if success {
             health.failed_attempts = 0;
             health.alive = true;
@@ -96,13 +338,128 @@ impl HealthController {
             health.alive = health.ever_alive && health.failed_attempts < 3;
         }
         let alive = health.alive;
+        let ever_alive = health.ever_alive;
         drop(health);
         if prev_alive != alive {
             if alive {
                 log::info!("Backend server {} is now alive", &server.addr);
             } else {
-                log::warn!("Backend server {} seems unreachable", &server.addr);
+                // sessions may outlive a blip, closing them is left to their own timeouts
+                log::warn!(
+                    "Backend server {} seems unreachable, no new players will be sent to it. Keeping its {} existing sessions",
+                    &server.addr,
+                    server.load.load(Ordering::Relaxed)
+                );
+            }
+            if let Some(observer) = observer {
+                let server_addr = server.addr;
+                tokio::spawn(async move { observer.on_health_change(server_addr, alive).await });
+            }
+            if let Some(webhook_url) = webhook_url {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                send_health_webhook(
+                    webhook_url,
+                    HealthWebhookPayload {
+                        server_addr: server.addr,
+                        alive,
+                        ever_alive,
+                        timestamp,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Gets the delay before checking the health of a server, to spread checks over the interval.
+///
+/// The delay of a server is always the same for a given interval, so that it is still
+/// checked once per interval. It leaves room for the check to time out before the next
+/// round, which would otherwise be skipped.
+///
+/// ## Arguments
+///
+/// * `addr` - Address of the server
+/// * `interval` - Interval between two checks
+fn check_offset(addr: SocketAddr, interval: Duration) -> Duration {
+    let mut hasher = DefaultHasher::new();
+    addr.hash(&mut hasher);
+    interval.saturating_sub(CHECK_TIMEOUT) * (hasher.finish() % 1000) as u32 / 1000
+}
+
+/// Sends a health webhook in the background.
+/// The request is retried once if it fails.
+///
+/// ## Arguments
+///
+/// * `url` - Webhook URL
+/// * `payload` - Payload to POST as JSON
+#[cfg(feature = "health-webhook")]
+fn send_health_webhook(url: String, payload: HealthWebhookPayload) {
+    tokio::spawn(async move {
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+        {
+            Ok(client) => client,
+            Err(err) => {
+                log::warn!("Could not create health webhook client: {:?}", err);
+                return;
+            }
+        };
+        for attempt in 1..=2 {
+            let result = client
+                .post(&url)
+                .json(&payload)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status());
+            match result {
+                Ok(_) => return,
+                Err(err) => log::warn!(
+                    "Could not send health webhook (attempt {}/2): {:?}",
+                    attempt,
+                    err
+                ),
             }
         }
+    });
+}
+
+/// Sends a health webhook in the background.
+/// The request is retried once if it fails.
+///
+/// ## Arguments
+///
+/// * `url` - Webhook URL
+/// * `payload` - Payload to POST as JSON
+#[cfg(not(feature = "health-webhook"))]
+fn send_health_webhook(_url: String, _payload: HealthWebhookPayload) {
+    log::warn!(
+        "Health webhooks require trakt to be built with the `health-webhook` feature. Ignoring."
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_offset_leaves_room_for_timeout() {
+        let interval = Duration::from_secs(30);
+        for port in 19000..19500 {
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let offset = check_offset(addr, interval);
+            assert!(offset + CHECK_TIMEOUT <= interval, "offset {:?}", offset);
+            assert_eq!(check_offset(addr, interval), offset);
+        }
+
+        // no room to spread checks over
+        let addr = SocketAddr::from(([127, 0, 0, 1], 19132));
+        assert_eq!(check_offset(addr, CHECK_TIMEOUT), Duration::ZERO);
+        assert_eq!(check_offset(addr, Duration::from_secs(1)), Duration::ZERO);
     }
 }
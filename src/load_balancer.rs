@@ -1,8 +1,9 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tokio::sync::{Mutex, RwLock};
 
@@ -29,12 +30,99 @@ struct LoadBalancerExclusiveState {
     /// Current backend servers. More may exist if the config reloaded removing backends but
     /// some clients are still connected to it.
     servers: Vec<Arc<BackendServer>>,
+    /// Since when all servers have been seen as dead, if they currently are.
+    all_dead_since: Option<Instant>,
+}
+
+/// Introspection of the state of a [`LoadBalancer`].
+#[derive(Debug, Clone)]
+pub struct LoadBalancerStats {
+    /// Current load balancing method.
+    pub method: config::LoadBalanceMethod,
+    /// Index of the server round robin will try next, if round robin is used.
+    pub round_robin_index: Option<usize>,
+    /// Number of times each active server was picked for a new player.
+    pub picks: HashMap<SocketAddr, u64>,
 }
 
 #[derive(Debug, Clone)]
 enum LoadBalanceAlgorithm {
     RoundRobin { index: usize },
     LeastConnected,
+    WeightedLeastConnected,
+}
+
+/// Upper bounds of the session duration histogram buckets.
+/// A last, unbounded bucket holds longer sessions.
+pub const SESSION_DURATION_BOUNDS: [Duration; 3] = [
+    Duration::from_secs(60),
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(30 * 60),
+];
+
+/// Number of buckets in a [`SessionHistogram`].
+pub const SESSION_DURATION_BUCKETS: usize = SESSION_DURATION_BOUNDS.len() + 1;
+
+/// Bucketed histogram of session durations.
+#[derive(Debug, Default)]
+pub struct SessionHistogram {
+    buckets: [AtomicU64; SESSION_DURATION_BUCKETS],
+}
+
+impl SessionHistogram {
+    /// Records a finished session.
+    ///
+    /// ## Arguments
+    ///
+    /// * `duration` - How long the session lasted
+    pub fn record(&self, duration: Duration) {
+        let bucket = SESSION_DURATION_BOUNDS
+            .iter()
+            .position(|bound| duration < *bound)
+            .unwrap_or(SESSION_DURATION_BOUNDS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of sessions recorded in each bucket.
+    pub fn counts(&self) -> [u64; SESSION_DURATION_BUCKETS] {
+        std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+}
+
+/// Window over which the connection rate of a server is computed.
+pub const CONNECT_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Rate of new connections, computed over a sliding window.
+#[derive(Debug, Default)]
+pub struct ConnectRate {
+    /// Times of the connections within the window.
+    connects: std::sync::Mutex<VecDeque<Instant>>,
+}
+
+impl ConnectRate {
+    /// Records a new connection.
+    pub fn record(&self) {
+        let now = Instant::now();
+        let mut connects = self.connects.lock().unwrap();
+        Self::prune(&mut connects, now);
+        connects.push_back(now);
+    }
+
+    /// Average number of connections per second over the window.
+    pub fn per_second(&self) -> f64 {
+        let mut connects = self.connects.lock().unwrap();
+        Self::prune(&mut connects, Instant::now());
+        connects.len() as f64 / CONNECT_RATE_WINDOW.as_secs_f64()
+    }
+
+    fn prune(connects: &mut VecDeque<Instant>, now: Instant) {
+        while let Some(time) = connects.front() {
+            if now.duration_since(*time) < CONNECT_RATE_WINDOW {
+                break;
+            }
+            connects.pop_front();
+        }
+    }
 }
 
 /// A [`BackendServer`] is a Minecraft Bedrock Edition server/proxy
@@ -47,6 +135,20 @@ pub struct BackendServer {
     pub health: RwLock<ServerHealth>,
     /// Current number of clients assigned to that server.
     pub load: AtomicUsize,
+    /// Relative capacity of the server, for weighted load balancing.
+    pub weight: AtomicU32,
+    /// Total number of bytes forwarded to the server.
+    pub bytes_up: AtomicU64,
+    /// Total number of bytes forwarded from the server to players.
+    pub bytes_down: AtomicU64,
+    /// Durations of the finished sessions of players connected to the server.
+    pub session_durations: SessionHistogram,
+    /// Rate of new connections to the server.
+    pub connect_rate: ConnectRate,
+    /// Number of players whose handshake the server never accepted.
+    pub rejected_joins: AtomicU64,
+    /// Number of times the load balancer picked the server for a new player.
+    pub picks: AtomicU64,
 }
 
 impl BackendServer {
@@ -55,6 +157,13 @@ impl BackendServer {
             addr,
             health: RwLock::new(ServerHealth::default()),
             load: AtomicUsize::new(0),
+            weight: AtomicU32::new(1),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            session_durations: SessionHistogram::default(),
+            connect_rate: ConnectRate::default(),
+            rejected_joins: AtomicU64::new(0),
+            picks: AtomicU64::new(0),
         }
     }
 }
@@ -80,6 +189,7 @@ impl LoadBalancer {
         let state = LoadBalancerExclusiveState {
             algo,
             servers: Vec::new(),
+            all_dead_since: None,
         };
         let __self = Self {
             config_provider,
@@ -103,6 +213,7 @@ impl LoadBalancer {
     /// * `reload` - Whether this is a reload
     async fn load_config(&self, reload: bool) {
         let config = self.config_provider.read().await;
+        // held until the end so that concurrent reloads apply one after the other
         let mut state = self.state.lock().await;
         let new_method = config
             .load_balance_method
@@ -115,11 +226,25 @@ impl LoadBalancer {
             ) | (
                 LoadBalanceAlgorithm::LeastConnected,
                 config::LoadBalanceMethod::LeastConnected
+            ) | (
+                LoadBalanceAlgorithm::WeightedLeastConnected,
+                config::LoadBalanceMethod::WeightedLeastConnected
             )
         );
         if algo_reset {
             state.algo = LoadBalanceAlgorithm::init(new_method);
         }
+        // servers in the order round robin would pick them next, to resume the rotation
+        // where it was once servers are added or removed
+        let rotation: Vec<SocketAddr> = match state.algo {
+            LoadBalanceAlgorithm::RoundRobin { index } => {
+                let count = state.servers.len();
+                (0..count)
+                    .map(|offset| state.servers[(index + offset) % count].addr)
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
         let mut new_count = 0;
         let mut seen: HashSet<SocketAddr> = HashSet::new();
         for config_server in config.backend.servers.iter() {
@@ -141,11 +266,14 @@ impl LoadBalancer {
                 );
                 continue;
             }
+            let weight = config_server.weight.unwrap_or(1);
             let active = state.servers.iter().find(|server| server.addr.eq(&addr));
-            if active.is_some() {
+            if let Some(server) = active {
+                server.weight.store(weight, Ordering::Relaxed);
                 continue;
             }
             let server = Arc::new(BackendServer::new(addr));
+            server.weight.store(weight, Ordering::Relaxed);
             state.servers.push(server.clone());
             new_count += 1;
             self.health_controller.register_server(server).await;
@@ -153,6 +281,13 @@ impl LoadBalancer {
         let server_count = state.servers.len();
         state.servers.retain(|server| seen.contains(&server.addr));
         let removed_count = server_count - state.servers.len();
+        let next_index = rotation
+            .iter()
+            .find_map(|addr| state.servers.iter().position(|server| server.addr.eq(addr)))
+            .unwrap_or(0);
+        if let LoadBalanceAlgorithm::RoundRobin { index } = &mut state.algo {
+            *index = next_index;
+        }
         if reload || removed_count > 0 {
             log::info!(
                 "Reloaded load balancer. There are now {} backend servers ({} added, {} removed)",
@@ -179,10 +314,63 @@ impl LoadBalancer {
         active.cloned()
     }
 
+    /// Gets all active backend servers.
+    pub async fn servers(&self) -> Vec<Arc<BackendServer>> {
+        let state = self.state.lock().await;
+        state.servers.clone()
+    }
+
     /// Gets the next backend server according to the load balancing method.
     ///
     /// Will return [`None`] if no server is available.
     pub async fn next(&self) -> Option<Arc<BackendServer>> {
+        let server = self.pick(true).await;
+        if let Some(server) = &server {
+            server.picks.fetch_add(1, Ordering::Relaxed);
+        }
+        server
+    }
+
+    /// Obtains the current state of the load balancer, to diagnose uneven distribution.
+    pub async fn stats(&self) -> LoadBalancerStats {
+        let state = self.state.lock().await;
+        let round_robin_index = match state.algo {
+            LoadBalanceAlgorithm::RoundRobin { index } => Some(index),
+            _ => None,
+        };
+        LoadBalancerStats {
+            method: state.algo.method(),
+            round_robin_index,
+            picks: state
+                .servers
+                .iter()
+                .map(|server| (server.addr, server.picks.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+
+    /// Gets the backend server that [`LoadBalancer::next`] would currently return,
+    /// without affecting the load balancing state.
+    ///
+    /// Will return [`None`] if no server is available.
+    #[inline]
+    pub async fn peek(&self) -> Option<Arc<BackendServer>> {
+        self.pick(false).await
+    }
+
+    /// Picks a backend server according to the load balancing method.
+    ///
+    /// ## Arguments
+    ///
+    /// * `advance` - Whether to advance the load balancing state (e.g. round robin index)
+    async fn pick(&self, advance: bool) -> Option<Arc<BackendServer>> {
+        let (all_dead_grace, route_when_all_dead) = {
+            let config = self.config_provider.read().await;
+            (
+                config.all_dead_grace_secs.map(Duration::from_secs),
+                config.route_when_all_dead.unwrap_or(true),
+            )
+        };
         let mut state = self.state.lock().await;
         let server_count = state.servers.len();
         if server_count == 0 {
@@ -191,47 +379,63 @@ impl LoadBalancer {
         // when all backend servers are marked as alive
         // it might be an issue specific to pings, hence we still
         // want to allow players to attempt joining even if health status is wrong
+        // servers that were never checked don't count as dead, so players
+        // aren't refused at startup before the first health checks complete
         let respect_alive_status = {
-            let mut alive_count = 0;
+            let mut pickable_count = 0;
             for server in state.servers.iter() {
                 let health = server.health.read().await;
-                if health.alive {
-                    alive_count += 1;
+                if health.is_pickable() {
+                    pickable_count += 1;
                 }
             }
-            alive_count > 0
+            pickable_count > 0
         };
+        if respect_alive_status {
+            if advance {
+                state.all_dead_since = None;
+            }
+        } else {
+            // a peek must not start the grace period
+            let all_dead_since = match state.all_dead_since {
+                Some(all_dead_since) => all_dead_since,
+                None if advance => *state.all_dead_since.insert(Instant::now()),
+                None => Instant::now(),
+            };
+            if !route_when_all_dead {
+                log::debug!("All backend servers are dead, no server is available");
+                return None;
+            }
+            if all_dead_grace.is_some_and(|grace| all_dead_since.elapsed() < grace) {
+                log::debug!("All backend servers are dead, waiting before ignoring health status");
+                return None;
+            }
+        }
         log::debug!(
-            "Getting next server from load balancer (algo: {:?}, respect_alive_status: {})",
+            "Getting next server from load balancer (algo: {:?}, respect_alive_status: {}, advance: {})",
             &state.algo,
-            respect_alive_status
+            respect_alive_status,
+            advance
         );
         match &state.algo {
-            LoadBalanceAlgorithm::RoundRobin { .. } => {
-                for _ in 0..server_count {
-                    let index = match &mut state.algo {
-                        LoadBalanceAlgorithm::RoundRobin { index } => {
-                            let prev_index = *index;
-                            if prev_index + 1 >= server_count {
-                                *index = 0;
-                            } else {
-                                *index += 1;
-                            }
-                            prev_index
-                        }
-                        _ => unreachable!(),
-                    };
-                    match state.servers.get(index) {
-                        Some(server) if respect_alive_status => {
-                            let health = server.health.read().await;
-                            if !health.alive {
-                                continue;
-                            }
-                            return Some(server.clone());
+            LoadBalanceAlgorithm::RoundRobin { index } => {
+                let start_index = *index;
+                for offset in 0..server_count {
+                    let index = (start_index + offset) % server_count;
+                    let server = &state.servers[index];
+                    if respect_alive_status {
+                        let health = server.health.read().await;
+                        if !health.is_pickable() {
+                            continue;
                         }
-                        Some(server) => return Some(server.clone()),
-                        _ => {}
                     }
+                    let server = server.clone();
+                    if advance {
+                        state.algo = LoadBalanceAlgorithm::RoundRobin {
+                            index: (index + 1) % server_count,
+                        };
+                    }
+                    return Some(server);
                 }
                 None
             }
@@ -243,7 +447,7 @@ impl LoadBalancer {
                     if load < min_load {
                         if respect_alive_status {
                             let health = server.health.read().await;
-                            if !health.alive {
+                            if !health.is_pickable() {
                                 continue;
                             }
                         }
@@ -253,6 +457,27 @@ impl LoadBalancer {
                 }
                 target
             }
+            LoadBalanceAlgorithm::WeightedLeastConnected => {
+                let mut min_score = (f64::MAX, usize::MAX);
+                let mut target = None;
+                for server in state.servers.iter() {
+                    let load = server.load.load(Ordering::Acquire);
+                    let weight = server.weight.load(Ordering::Relaxed).max(1);
+                    // ties are broken by the lowest absolute load
+                    let score = (load as f64 / weight as f64, load);
+                    if score < min_score {
+                        if respect_alive_status {
+                            let health = server.health.read().await;
+                            if !health.is_pickable() {
+                                continue;
+                            }
+                        }
+                        min_score = score;
+                        target = Some(server.clone());
+                    }
+                }
+                target
+            }
         }
     }
 }
@@ -263,6 +488,199 @@ impl LoadBalanceAlgorithm {
         match method {
             config::LoadBalanceMethod::RoundRobin => Self::RoundRobin { index: 0 },
             config::LoadBalanceMethod::LeastConnected => Self::LeastConnected,
+            config::LoadBalanceMethod::WeightedLeastConnected => Self::WeightedLeastConnected,
+        }
+    }
+
+    /// Gets the configured method this algorithm implements.
+    pub fn method(&self) -> config::LoadBalanceMethod {
+        match self {
+            Self::RoundRobin { .. } => config::LoadBalanceMethod::RoundRobin,
+            Self::LeastConnected => config::LoadBalanceMethod::LeastConnected,
+            Self::WeightedLeastConnected => config::LoadBalanceMethod::WeightedLeastConnected,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    async fn test_balancer(extra: &str, servers: &[&str]) -> LoadBalancer {
+        let servers = servers
+            .iter()
+            .map(|server| format!("{{ {} }}", server))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let config = toml::from_str(&format!(
+            r#"
+bind_address = "127.0.0.1:19132"
+proxy_bind = "127.0.0.1:0"
+{}
+
+[backend]
+health_check_rate = 60
+motd_refresh_rate = 60
+servers = [{}]
+"#,
+            extra, servers
+        ))
+        .unwrap();
+        let config_provider = Arc::new(ConfigProvider::new(PathBuf::new(), config));
+        let health_controller = Arc::new(HealthController::new(config_provider.clone(), None));
+        LoadBalancer::init(config_provider, health_controller).await
+    }
+
+    async fn set_alive(server: &BackendServer, alive: bool) {
+        let mut health = server.health.write().await;
+        health.checked = true;
+        health.alive = alive;
+        health.ever_alive |= alive;
+    }
+
+    #[tokio::test]
+    async fn unchecked_servers_are_not_dead() {
+        let balancer = test_balancer(
+            "route_when_all_dead = false",
+            &[
+                r#"address = "127.0.0.1:19133""#,
+                r#"address = "127.0.0.1:19134""#,
+            ],
+        )
+        .await;
+        let servers = balancer.servers().await;
+
+        // before the first health checks complete
+        assert_eq!(balancer.next().await.unwrap().addr, servers[0].addr);
+        assert_eq!(balancer.next().await.unwrap().addr, servers[1].addr);
+        assert!(balancer.state.lock().await.all_dead_since.is_none());
+
+        // a dead server is skipped while the other one is unknown
+        set_alive(&servers[0], false).await;
+        assert_eq!(balancer.next().await.unwrap().addr, servers[1].addr);
+        assert_eq!(balancer.next().await.unwrap().addr, servers[1].addr);
+
+        set_alive(&servers[1], false).await;
+        assert!(balancer.next().await.is_none());
+        assert!(balancer.state.lock().await.all_dead_since.is_some());
+    }
+
+    #[tokio::test]
+    async fn weighted_least_connected() {
+        let balancer = test_balancer(
+            r#"load_balance_method = "weighted_least_connected""#,
+            &[
+                r#"address = "127.0.0.1:19133""#,
+                r#"address = "127.0.0.1:19134", weight = 2"#,
+            ],
+        )
+        .await;
+        let servers = balancer.servers().await;
+        assert_eq!(servers[1].weight.load(Ordering::Relaxed), 2);
+
+        // the load relative to the weight is compared
+        servers[0].load.store(2, Ordering::Release);
+        servers[1].load.store(2, Ordering::Release);
+        assert_eq!(balancer.next().await.unwrap().addr, servers[1].addr);
+        servers[1].load.store(3, Ordering::Release);
+        assert_eq!(balancer.next().await.unwrap().addr, servers[1].addr);
+
+        // equal ratios are broken by the lowest absolute load
+        servers[0].load.store(1, Ordering::Release);
+        servers[1].load.store(2, Ordering::Release);
+        assert_eq!(balancer.next().await.unwrap().addr, servers[0].addr);
+        servers[0].load.store(2, Ordering::Release);
+        servers[1].load.store(4, Ordering::Release);
+        assert_eq!(balancer.next().await.unwrap().addr, servers[0].addr);
+
+        // dead servers are skipped whatever their load
+        set_alive(&servers[0], false).await;
+        assert_eq!(balancer.next().await.unwrap().addr, servers[1].addr);
+    }
+
+    #[tokio::test]
+    async fn route_when_all_dead() {
+        let servers = [
+            r#"address = "127.0.0.1:19133""#,
+            r#"address = "127.0.0.1:19134""#,
+        ];
+
+        // disabled: no server until one is alive again, including at startup
+        let balancer = test_balancer("route_when_all_dead = false", &servers).await;
+        let backend = balancer.servers().await;
+        assert!(balancer.next().await.is_some());
+        set_alive(&backend[0], false).await;
+        set_alive(&backend[1], false).await;
+        assert!(balancer.next().await.is_none());
+        assert!(balancer.peek().await.is_none());
+        set_alive(&backend[1], true).await;
+        assert_eq!(balancer.next().await.unwrap().addr, backend[1].addr);
+        assert!(balancer.state.lock().await.all_dead_since.is_none());
+
+        // enabled (default): health status is ignored once all servers are dead
+        let balancer = test_balancer("", &servers).await;
+        let backend = balancer.servers().await;
+        assert!(balancer.next().await.is_some());
+        set_alive(&backend[0], false).await;
+        set_alive(&backend[1], false).await;
+        assert_eq!(balancer.next().await.unwrap().addr, backend[1].addr);
+        assert_eq!(balancer.next().await.unwrap().addr, backend[0].addr);
+        assert!(balancer.state.lock().await.all_dead_since.is_some());
+
+        // with a grace period, only once it has elapsed
+        let balancer = test_balancer("all_dead_grace_secs = 60", &servers).await;
+        let backend = balancer.servers().await;
+        set_alive(&backend[0], false).await;
+        set_alive(&backend[1], false).await;
+        assert!(balancer.next().await.is_none());
+        balancer.state.lock().await.all_dead_since =
+            Instant::now().checked_sub(Duration::from_secs(61));
+        assert!(balancer.next().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn peek_does_not_advance() {
+        let balancer = test_balancer(
+            "",
+            &[
+                r#"address = "127.0.0.1:19133""#,
+                r#"address = "127.0.0.1:19134""#,
+            ],
+        )
+        .await;
+        let servers = balancer.servers().await;
+
+        for _ in 0..3 {
+            assert_eq!(balancer.peek().await.unwrap().addr, servers[0].addr);
+        }
+        let stats = balancer.stats().await;
+        assert_eq!(stats.round_robin_index, Some(0));
+        assert!(stats.picks.iter().all(|(_, picks)| *picks == 0));
+
+        // peeks follow the rotation without moving it
+        assert_eq!(balancer.next().await.unwrap().addr, servers[0].addr);
+        assert_eq!(balancer.peek().await.unwrap().addr, servers[1].addr);
+        assert_eq!(balancer.next().await.unwrap().addr, servers[1].addr);
+        assert_eq!(balancer.stats().await.round_robin_index, Some(0));
+
+        // a dead server is skipped the same way as by next
+        set_alive(&servers[0], false).await;
+        assert_eq!(balancer.peek().await.unwrap().addr, servers[1].addr);
+        assert_eq!(balancer.stats().await.round_robin_index, Some(0));
+        assert_eq!(servers[1].picks.load(Ordering::Relaxed), 1);
+
+        // the all dead grace period only starts once a player is routed
+        set_alive(&servers[1], false).await;
+        assert!(balancer.peek().await.is_some());
+        assert!(balancer.state.lock().await.all_dead_since.is_none());
+        assert!(balancer.next().await.is_some());
+        let all_dead_since = balancer.state.lock().await.all_dead_since;
+        assert!(all_dead_since.is_some());
+
+        // nor is it reset by a peek
+        set_alive(&servers[1], true).await;
+        assert_eq!(balancer.peek().await.unwrap().addr, servers[1].addr);
+        assert_eq!(balancer.state.lock().await.all_dead_since, all_dead_since);
+    }
+}
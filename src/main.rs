@@ -1,21 +1,31 @@
-use std::{path::PathBuf, process::exit, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
 use config::ConfigProvider;
-use log::LevelFilter;
-use proxy::RaknetProxy;
-use simple_logger::SimpleLogger;
-use snapshot::RaknetProxySnapshot;
+use load_balancer::CONNECT_RATE_WINDOW;
+use proxy::{RaknetProxy, RaknetProxyBuilder};
+use snapshot::{RaknetProxySnapshot, SnapshotFormat};
 use tokio::io::AsyncBufReadExt;
 
 mod config;
+mod events;
 mod health;
 mod load_balancer;
 mod motd;
 mod proxy;
+mod proxy_protocol;
 mod raknet;
+mod rate_limit;
 mod scheduler;
 mod snapshot;
+mod socket;
+mod ulimit;
 
 #[derive(Parser)]
 #[command(version, about)]
@@ -35,46 +45,128 @@ struct Args {
     /// Raise the maximum number of open files allowed to avoid issues.
     ///
     /// Not enabled by default as it may not work in all environments.
-    #[arg(long)]
+    #[arg(long, alias = "auto-ulimit")]
     raise_ulimit: bool,
     /// File to read & write the recovery snapshot to.
     #[arg(long, value_name = "FILE", default_value = ".trakt_recover")]
     recovery_snapshot_file: Option<PathBuf>,
+    /// Never read nor write a recovery snapshot.
+    #[arg(long)]
+    no_recovery: bool,
+    /// Maximum age, in seconds, of a recovery snapshot to recover from.
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    recovery_max_age: u64,
+    /// Minimum percentage of clients to recover from a snapshot
+    /// before recovery is reported as failed.
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 50,
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    recovery_min_success: u8,
+    /// Compress the recovery snapshot with gzip.
+    ///
+    /// Requires trakt to be built with the `snapshot-compression` feature.
+    #[arg(long)]
+    compress_snapshot: bool,
+    /// Write the recovery snapshot as pretty-printed JSON.
+    #[arg(long)]
+    pretty_snapshot: bool,
+    /// Also write the recovery snapshot every this many seconds, to recover from crashes.
+    ///
+    /// Should be lower than the recovery max age for the snapshot to be usable.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_interval: Option<u64>,
+    /// On shutdown, notify players of it and wait up to this many seconds for their
+    /// sessions to close, instead of taking a recovery snapshot.
+    #[arg(long, value_name = "SECONDS")]
+    drain_timeout: Option<u64>,
 }
 
 fn main() {
     let args = Args::parse();
-    let log_level = match args.verbose {
-        0 => LevelFilter::Info,
-        1 => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
+    init_logger(&args);
+
+    let fd_limit = if args.raise_ulimit {
+        let before = ulimit::soft_fd_limit();
+        match fdlimit::raise_fd_limit() {
+            Some(ulimit) => {
+                match before {
+                    Some(before) => log::info!("Raised ulimit from {} to {}", before, ulimit),
+                    None => log::info!("Raised ulimit to {}", ulimit),
+                }
+                Some(ulimit)
+            }
+            None => {
+                log::warn!("Could not raise ulimit on this platform");
+                before
+            }
+        }
+    } else {
+        ulimit::soft_fd_limit()
     };
-    SimpleLogger::new()
-        .with_level(log_level)
-        .with_colors(!args.no_color)
-        .init()
-        .unwrap();
 
-    if args.raise_ulimit {
-        let ulimit = fdlimit::raise_fd_limit().unwrap_or(0);
-        log::info!("Raised ulimit to {}", ulimit);
-    }
+    let recovery_snapshot_file = if args.no_recovery {
+        None
+    } else {
+        Some(
+            args.recovery_snapshot_file
+                .as_ref()
+                .map(PathBuf::clone)
+                .unwrap_or_else(|| PathBuf::from_str(".trakt_recover").unwrap()),
+        )
+    };
+    let snapshot = match &recovery_snapshot_file {
+        Some(recovery_snapshot_file) => read_recovery_snapshot(recovery_snapshot_file, &args),
+        None => None,
+    };
 
-    let recovery_snapshot_file = args
-        .recovery_snapshot_file
+    let config_file = args
+        .config
         .as_ref()
         .map(PathBuf::clone)
-        .unwrap_or_else(|| PathBuf::from_str(".trakt_recover").unwrap());
-    let snapshot = match snapshot::read_snapshot_file(&recovery_snapshot_file) {
-        Ok(Some(snapshot))
-            if snapshot
-                .taken_at
-                .elapsed()
-                .map(|elapsed| elapsed >= Duration::from_secs(10))
-                .unwrap_or(true) =>
-        {
+        .unwrap_or_else(|| PathBuf::from_str("config.toml").unwrap());
+    let config_provider = if let Some(snapshot) = &snapshot {
+        ConfigProvider::new(config_file, snapshot.config.clone())
+    } else {
+        match config::read_config(config_file.clone()) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!(
+                    "Could not read configuration file ({}): {}",
+                    config_file.to_string_lossy(),
+                    err
+                );
+                return;
+            }
+        }
+    };
+    run(
+        config_provider,
+        args,
+        recovery_snapshot_file,
+        snapshot,
+        fd_limit,
+    );
+}
+
+/// Reads the recovery snapshot, if there is one recent enough.
+///
+/// ## Arguments
+///
+/// * `recovery_snapshot_file` - Recovery snapshot file
+/// * `args` - Command line arguments
+fn read_recovery_snapshot(
+    recovery_snapshot_file: &Path,
+    args: &Args,
+) -> Option<RaknetProxySnapshot> {
+    let recovery_max_age = Duration::from_secs(args.recovery_max_age);
+    match snapshot::read_snapshot_file(recovery_snapshot_file) {
+        Ok(Some(snapshot)) if snapshot.has_expired(recovery_max_age) => {
             log::warn!(
-                "Recovery snapshot file exsits but dates back from more than 10 seconds. Ignoring."
+                "Recovery snapshot file exsits but dates back from more than {} seconds. Ignoring.",
+                args.recovery_max_age
             );
             None
         }
@@ -92,37 +184,49 @@ fn main() {
             );
             None
         }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+fn init_logger(args: &Args) {
+    use log::LevelFilter;
+    use simple_logger::SimpleLogger;
+
+    let log_level = match args.verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
     };
+    SimpleLogger::new()
+        .with_level(log_level)
+        .with_colors(!args.no_color)
+        .init()
+        .unwrap();
+}
 
-    let config_file = args
-        .config
-        .as_ref()
-        .map(PathBuf::clone)
-        .unwrap_or_else(|| PathBuf::from_str("config.toml").unwrap());
-    let config_provider = if let Some(snapshot) = &snapshot {
-        ConfigProvider::new(config_file, snapshot.config.clone())
-    } else {
-        match config::read_config(config_file.clone()) {
-            Ok(config) => config,
-            Err(err) => {
-                log::error!(
-                    "Could not read configuration file ({}): {}",
-                    config_file.to_string_lossy(),
-                    err
-                );
-                return;
-            }
-        }
+#[cfg(feature = "tracing")]
+fn init_logger(args: &Args) {
+    use tracing::level_filters::LevelFilter;
+
+    let log_level = match args.verbose {
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
     };
-    run(config_provider, args, recovery_snapshot_file, snapshot);
+    // also installs a `log` compatibility layer so `log` records become tracing events
+    tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_ansi(!args.no_color)
+        .init();
 }
 
 #[tokio::main]
 async fn run(
     config_provider: ConfigProvider,
     args: Args,
-    recovery_snapshot_file: PathBuf,
+    recovery_snapshot_file: Option<PathBuf>,
     snapshot: Option<RaknetProxySnapshot>,
+    fd_limit: Option<u64>,
 ) {
     let bind_address = if let Some(snapshot) = &snapshot {
         snapshot.player_proxy_bind.clone()
@@ -132,15 +236,43 @@ async fn run(
         config.bind_address.clone()
     };
     let config_provider = Arc::new(config_provider);
-    let proxy = RaknetProxy::bind(
-        bind_address,
-        config_provider.clone(),
-        recovery_snapshot_file,
-    )
-    .await
-    .unwrap();
+    if args.compress_snapshot && !cfg!(feature = "snapshot-compression") {
+        log::warn!(
+            "Snapshot compression requires trakt to be built with the `snapshot-compression` feature. Ignoring."
+        );
+    }
+    let snapshot_format = SnapshotFormat {
+        compress: args.compress_snapshot && cfg!(feature = "snapshot-compression"),
+        pretty: args.pretty_snapshot,
+    };
+    let events_config = config_provider.read().await.events.clone();
+    let observer = match &events_config {
+        Some(events_config) => events::init_sink(events_config).await,
+        None => None,
+    };
+    let proxy = RaknetProxyBuilder::new(config_provider.clone())
+        .recovery_snapshot_file(recovery_snapshot_file)
+        .snapshot_format(snapshot_format)
+        .observer(observer)
+        .bind(bind_address)
+        .await
+        .unwrap();
     if let Some(snapshot) = snapshot {
-        proxy.recover_from_snapshot(snapshot).await;
+        let outcome = proxy.recover_from_snapshot(snapshot).await;
+        if outcome.success_rate() < args.recovery_min_success as f64 {
+            log::error!(
+                "Only {} out of {} clients could be recovered from the recovery snapshot",
+                outcome.recovered,
+                outcome.total
+            );
+        } else {
+            log::info!(
+                "Recovered {} out of {} clients from the recovery snapshot",
+                outcome.recovered,
+                outcome.total
+            );
+        }
+        // the on-disk config takes over from the snapshot config either way
         tokio::spawn({
             let config_provider = config_provider.clone();
             async move {
@@ -148,16 +280,37 @@ async fn run(
             }
         });
     }
+    if let Some(snapshot_interval) = args.snapshot_interval.filter(|_| !args.no_recovery) {
+        if snapshot_interval >= args.recovery_max_age {
+            log::warn!(
+                "Snapshot interval ({}s) is not lower than the recovery max age ({}s), periodic snapshots may be too old to recover from",
+                snapshot_interval,
+                args.recovery_max_age
+            );
+        }
+        tokio::spawn({
+            let proxy = proxy.clone();
+            async move {
+                proxy
+                    .run_periodic_snapshots(Duration::from_secs(snapshot_interval))
+                    .await;
+            }
+        });
+    }
+    if let Some(fd_limit) = fd_limit {
+        tokio::spawn(ulimit::watch_fd_usage(fd_limit));
+    }
     if !args.ignore_stdin {
         tokio::spawn({
             let proxy = proxy.clone();
             let config_provider = config_provider.clone();
             async move {
                 log::info!("Console commands enabled");
-                run_stdin_handler(proxy, config_provider).await;
+                run_stdin_handler(proxy, config_provider, fd_limit).await;
             }
         });
     }
+    let drain_timeout = args.drain_timeout.map(Duration::from_secs);
     tokio::spawn({
         let proxy = proxy.clone();
         async move {
@@ -170,7 +323,17 @@ async fn run(
                             exit(1);
                         }
                         log::info!("Shutdown requested...");
-                        if proxy.take_and_write_snapshot().await {
+                        if let Some(drain_timeout) = drain_timeout {
+                            if shutdown_requests == 1 {
+                                tokio::spawn({
+                                    let proxy = proxy.clone();
+                                    async move {
+                                        proxy.shutdown_graceful(drain_timeout).await;
+                                        exit(0);
+                                    }
+                                });
+                            }
+                        } else if proxy.take_and_write_snapshot().await {
                             exit(0);
                         }
                     }
@@ -187,7 +350,14 @@ async fn run(
     proxy.cleanup().await;
 }
 
-async fn run_stdin_handler(proxy: Arc<RaknetProxy>, config_provider: Arc<ConfigProvider>) {
+/// Number of clients listed per page by the `clients` command.
+const CLIENTS_PAGE_SIZE: usize = 20;
+
+async fn run_stdin_handler(
+    proxy: Arc<RaknetProxy>,
+    config_provider: Arc<ConfigProvider>,
+    fd_limit: Option<u64>,
+) {
     let mut reader = tokio::io::BufReader::new(tokio::io::stdin());
     loop {
         let mut buf = String::new();
@@ -210,6 +380,123 @@ async fn run_stdin_handler(proxy: Arc<RaknetProxy>, config_provider: Arc<ConfigP
                     overview.per_server
                 )
             }
+            "stats" => {
+                let stats = proxy.stats().await;
+                log::info!(
+                    "Rate limited: {} handshakes, {} pings",
+                    stats.rate_limited_handshakes,
+                    stats.rate_limited_pings
+                );
+                log::info!(
+                    "Resources: {} open UDP sockets ({} pooled, ulimit: {}), ~{} bytes used by clients",
+                    stats.open_sockets,
+                    stats.pooled_sockets,
+                    fd_limit
+                        .map(|limit| limit.to_string())
+                        .unwrap_or_else(|| "unknown".to_owned()),
+                    stats.clients_memory
+                );
+                for (addr, traffic) in stats.traffic.iter() {
+                    log::info!(
+                        "Server {}: {} bytes up, {} bytes down",
+                        addr,
+                        traffic.bytes_up,
+                        traffic.bytes_down
+                    );
+                }
+                log::info!(
+                    "Connections: {:.1}/s over the last {}s",
+                    stats.connect_rates.values().sum::<f64>(),
+                    CONNECT_RATE_WINDOW.as_secs()
+                );
+                for (addr, rate) in stats.connect_rates.iter() {
+                    log::info!("Server {}: {:.1} connections/s", addr, rate);
+                }
+                match stats.load_balancer.round_robin_index {
+                    Some(index) => log::info!(
+                        "Load balancer: {:?} (next index: {})",
+                        stats.load_balancer.method,
+                        index
+                    ),
+                    None => log::info!("Load balancer: {:?}", stats.load_balancer.method),
+                }
+                for (addr, picks) in stats.load_balancer.picks.iter() {
+                    log::info!("Server {}: picked {} times", addr, picks);
+                }
+                for (addr, rejected) in stats.rejected_joins.iter() {
+                    if *rejected > 0 {
+                        log::info!("Server {}: {} rejected joins", addr, rejected);
+                    }
+                }
+                for (addr, counts) in stats.session_durations.iter() {
+                    log::info!(
+                        "Server {}: sessions <1m: {}, 1-5m: {}, 5-30m: {}, >30m: {}",
+                        addr,
+                        counts[0],
+                        counts[1],
+                        counts[2],
+                        counts[3]
+                    );
+                }
+                for (addr, motd) in stats.server_motds.iter() {
+                    log::info!(
+                        "Server {}: version {} (protocol {}), {}/{} players",
+                        addr,
+                        motd.version_name,
+                        motd.protocol_version,
+                        motd.player_count,
+                        motd.max_player_count
+                    );
+                }
+                for (source, elapsed) in stats.motd_last_success.iter() {
+                    match elapsed {
+                        Some(elapsed) => log::info!(
+                            "MOTD source {}: last fetched {}s ago",
+                            source,
+                            elapsed.as_secs()
+                        ),
+                        None => log::info!("MOTD source {}: never fetched", source),
+                    }
+                }
+            }
+            cmd if cmd == "clients" || cmd.starts_with("clients ") => {
+                let page = match cmd["clients".len()..].trim() {
+                    "" => 1,
+                    page => match page.parse::<usize>() {
+                        Ok(page) if page > 0 => page,
+                        _ => {
+                            log::warn!("Usage: clients [page]");
+                            continue;
+                        }
+                    },
+                };
+                let (clients, total) = proxy
+                    .list_clients((page - 1) * CLIENTS_PAGE_SIZE, CLIENTS_PAGE_SIZE)
+                    .await;
+                log::info!(
+                    "Active clients (page {}/{}, {} total):",
+                    page,
+                    total.div_ceil(CLIENTS_PAGE_SIZE).max(1),
+                    total
+                );
+                for client in clients.iter() {
+                    log::info!(
+                        "{} -> {} ({}, {}s)",
+                        client.addr,
+                        client.server_addr,
+                        if client.connected {
+                            "connected"
+                        } else {
+                            "handshake"
+                        },
+                        client.age.as_secs()
+                    );
+                }
+            }
+            "peek" => match proxy.peek_server().await {
+                Some(addr) => log::info!("The next player would be sent to {}", addr),
+                None => log::info!("No server is available for the next player"),
+            },
             "recover-able-shutdown" | "ras" => {
                 proxy.take_and_write_snapshot().await;
             }
@@ -1,10 +1,16 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use tokio::sync::{RwLock, Semaphore};
 
 use crate::{
-    config::ConfigProvider,
-    raknet::ping::{self, Motd},
+    config::{ConfigProvider, ProxyProtocolMode, ProxyProtocolVersion},
+    raknet::ping::{Motd, MotdClient, PingOptions},
 };
 
 /// A controller that periodically fetches MOTD information
@@ -17,6 +23,8 @@ pub struct MOTDReflector {
 
     /// Last successful MOTD response, if any.
     last_motd: RwLock<Option<Motd>>,
+    /// Time of the last successful MOTD response, per source.
+    last_success_at: RwLock<HashMap<String, Instant>>,
 }
 
 impl MOTDReflector {
@@ -25,6 +33,7 @@ impl MOTDReflector {
             execute_lock: Semaphore::new(1),
             config_provider,
             last_motd: RwLock::new(None),
+            last_success_at: RwLock::new(HashMap::new()),
         }
     }
 
@@ -33,31 +42,60 @@ impl MOTDReflector {
         self.last_motd.read().await.clone()
     }
 
+    /// Returns the time elapsed since the last successful MOTD response of each
+    /// configured source, or `None` for sources that never responded.
+    pub async fn since_last_success(&self) -> HashMap<String, Option<Duration>> {
+        let sources = {
+            let config = self.config_provider.read().await;
+            config.backend.motd_sources_or_default()
+        };
+        let last_success_at = self.last_success_at.read().await;
+        sources
+            .into_iter()
+            .map(|source| {
+                let elapsed = last_success_at.get(&source).map(Instant::elapsed);
+                (source, elapsed)
+            })
+            .collect()
+    }
+
     /// Fetches the MOTD.
     pub async fn execute(&self) {
         let _permit = self.execute_lock.acquire().await;
-        let (local_addr, sources, proxy_protocol) = {
+        let (local_addr, sources) = {
             let config = self.config_provider.read().await;
-            let sources = if let Some(source) = &config.backend.motd_source {
-                vec![source.clone()]
-            } else {
-                config
-                    .backend
-                    .servers
-                    .iter()
-                    .map(|server| server.address.clone())
-                    .collect()
-            };
             let proxy_protocol = config.proxy_protocol.unwrap_or(true);
-            (config.proxy_bind.clone(), sources, proxy_protocol)
+            let sources = config
+                .backend
+                .motd_sources_or_default()
+                .into_iter()
+                .map(|source| {
+                    let header = SocketAddr::from_str(&source)
+                        .map(|addr| {
+                            (
+                                config.proxy_protocol_version_for(addr),
+                                config.proxy_protocol_mode_for(addr),
+                            )
+                        })
+                        .unwrap_or((ProxyProtocolVersion::V2, ProxyProtocolMode::Udp));
+                    let proxy_protocol = Some(header).filter(|_| proxy_protocol);
+                    (source, proxy_protocol)
+                })
+                .collect::<Vec<_>>();
+            (config.proxy_bind.clone(), sources)
         };
         log::debug!(
             "Fetching MOTD information from backend ({} sources)...",
             sources.len()
         );
-        let timeout = Duration::from_secs(5);
-        for source in sources.into_iter() {
-            match ping::ping(&local_addr, &source, proxy_protocol, timeout).await {
+        let client = MotdClient::with_local_addr(local_addr);
+        for (source, proxy_protocol) in sources.into_iter() {
+            let options = PingOptions {
+                timeout: Duration::from_secs(5),
+                proxy_protocol,
+                ..Default::default()
+            };
+            match client.ping(&source, &options).await {
                 Ok(motd) => {
                     log::debug!(
                         "Successfully fetched MOTD information from source {}: {:?}",
@@ -66,6 +104,10 @@ impl MOTDReflector {
                     );
                     let mut w = self.last_motd.write().await;
                     *w = Some(motd);
+                    self.last_success_at
+                        .write()
+                        .await
+                        .insert(source, Instant::now());
                 }
                 Err(err) => {
                     log::warn!(
@@ -2,30 +2,67 @@ use rand::Rng;
 use std::collections::hash_map::Entry;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::atomic::Ordering;
-use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tokio::sync::mpsc;
 
-use crate::config::ConfigProvider;
+use crate::config::{ConfigProvider, PlayerCountSource, ProxyProtocolMode, ProxyProtocolVersion};
+use crate::events::{ClientInfo, ProxyObserver};
 use crate::health::HealthController;
-use crate::load_balancer::{BackendServer, LoadBalancer};
+use crate::load_balancer::{
+    BackendServer, LoadBalancer, LoadBalancerStats, SESSION_DURATION_BUCKETS,
+};
 use crate::motd::MOTDReflector;
 use crate::raknet::{
-    datatypes::ReadBuf,
-    frame::Frame,
-    message::{Message, MessageUnconnectedPing, MessageUnconnectedPong, RaknetMessage},
+    datatypes::{ReadBuf, WriteBuf},
+    frame::{Frame, FrameFragment, Reliability},
+    message::{
+        Message, MessageAlreadyConnected, MessageError, MessageIncompatibleProtocolVersion,
+        MessageNoFreeIncomingConnection, MessageOpenConnectionRequest1, MessageUnconnectedPing,
+        MessageUnconnectedPong, RaknetMessage,
+    },
+    ping::Motd,
+    reassembly::FragmentReassembler,
+    ProtocolVersion,
 };
+use crate::rate_limit::RateLimiter;
 use crate::scheduler::Scheduler;
-use crate::snapshot::{RaknetClientSnapshot, RaknetProxySnapshot};
-use crate::{raknet, snapshot};
-use bytes::{Buf, Bytes};
+use crate::snapshot::{
+    RaknetClientSnapshot, RaknetProxySnapshot, SnapshotFormat, SNAPSHOT_VERSION,
+};
+use crate::socket::{self, SocketPool, UdpSocketOptions};
+use crate::{proxy_protocol, raknet, snapshot};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::Serialize;
 use tokio::{
     net::{ToSocketAddrs, UdpSocket},
-    sync::{RwLock, Semaphore},
+    sync::{Mutex, RwLock, Semaphore},
+    time::MissedTickBehavior,
 };
 
-use ppp::v2 as haproxy;
+/// Number of datagrams the buffer incoming player datagrams are split off can hold.
+///
+/// Datagrams are handled concurrently, so it should fit plenty of them for
+/// the allocation to be reused rather than growing a new one.
+const RECV_BUFFER_DATAGRAMS: usize = 64;
+
+/// Number of datagrams in a row from the server only carrying retransmitted reliable frames,
+/// with nothing received from the player meanwhile, after which the connection is considered lost.
+const RETRANSMIT_BURST_THRESHOLD: usize = 32;
+
+/// Number of keepalive pings in a row the server can leave unanswered before the client is closed.
+const KEEPALIVE_MAX_UNANSWERED: usize = 3;
+
+/// Maximum size, in bytes, of fragments buffered per client and direction for reassembly.
+const FRAGMENT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Time within which the server must have sent session data for a connection
+/// to be considered alive when the player requests a new one.
+const SERVER_ACTIVITY_WINDOW: Duration = Duration::from_secs(5);
+
+/// Minimum time between two warnings about datagrams filling the receive buffer.
+const TRUNCATION_WARN_INTERVAL: Duration = Duration::from_secs(60);
 
 /// Raknet proxy server that manage connections and use
 /// the load balancers to the server for new connections.
@@ -54,9 +91,29 @@ pub struct RaknetProxy {
     health_controller: Arc<HealthController>,
     /// Scheduler.
     scheduler: Scheduler,
+    /// Whether the proxy is draining, i.e. refusing new connections.
+    draining: AtomicBool,
+    /// Per-IP rate limiter for new connections.
+    handshake_limiter: RateLimiter,
+    /// Per-IP rate limiter for unconnected pings.
+    ping_limiter: RateLimiter,
+    /// Number of clients in [`ConnectionStage::Handshake`].
+    handshake_count: Arc<AtomicUsize>,
+    /// Number of clients in [`ConnectionStage::Connected`].
+    connected_count: Arc<AtomicUsize>,
+    /// Pool of Proxy <-> Server sockets, if enabled.
+    socket_pool: Option<Arc<SocketPool>>,
+    /// Datagrams that filled the receive buffer, shared with clients.
+    truncation_warnings: Arc<TruncationWarnings>,
 
-    /// Recovery snapshot file.
-    recovery_snapshot_file: PathBuf,
+    /// Recovery snapshot file, if recovery is enabled.
+    recovery_snapshot_file: Option<PathBuf>,
+    /// How recovery snapshots are written.
+    snapshot_format: SnapshotFormat,
+    /// Lock held while writing a snapshot, so that writes don't interleave.
+    snapshot_lock: Mutex<()>,
+    /// Observer notified of what happens in the proxy.
+    observer: Option<Arc<dyn ProxyObserver>>,
 }
 
 /// A client to the proxy.
@@ -71,11 +128,64 @@ struct RaknetClient {
     /// UDP socket for Player <-> Proxy traffic.
     proxy_udp_sock: Arc<UdpSocket>,
     /// UDP socket for Proxy <-> Server traffic.
-    udp_sock: UdpSocket,
+    udp_sock: Arc<UdpSocket>,
+    /// Address `udp_sock` was bound with.
+    proxy_bind: String,
     /// Cached local socket address of `udp_sock`.
     udp_sock_addr: SocketAddr,
+    /// Pool `udp_sock` is returned to once the client is dropped, if enabled.
+    socket_pool: Option<Arc<SocketPool>>,
+    /// Whether `udp_sock` can be handed to another player once the client is dropped.
+    recycle_socket: AtomicBool,
     /// Connection stage.
     stage: RwLock<ConnectionStage>,
+    /// When the client was created.
+    created_at: Instant,
+    /// When the client reached [`ConnectionStage::Connected`], if it did.
+    connected_at: std::sync::Mutex<Option<Instant>>,
+    /// Size of the buffer datagrams from the server are received into.
+    recv_buffer_size: usize,
+    /// Time without data from the server after which the connection is closed.
+    timeout: Duration,
+    /// Time without data from the player after which the connection is closed, if at all.
+    player_timeout: Option<Duration>,
+    /// Time after which the connection is closed if the server hasn't accepted the handshake, if at all.
+    handshake_timeout: Option<Duration>,
+    /// Time of the last datagram from the server that wasn't a keepalive reply.
+    last_server_activity: std::sync::Mutex<Instant>,
+    /// Time of the last datagram from the player, in milliseconds since `created_at`.
+    last_player_activity: AtomicU64,
+    /// Interval at which to ping the server, if at all.
+    keepalive_interval: Option<Duration>,
+    /// Number of keepalive pings in a row the server didn't answer.
+    unanswered_keepalives: AtomicUsize,
+    /// Number of bytes forwarded to the server.
+    bytes_to_server: AtomicU64,
+    /// Number of bytes forwarded to the player.
+    bytes_to_player: AtomicU64,
+    /// Observer notified of what happens in the proxy.
+    observer: Option<Arc<dyn ProxyObserver>>,
+    /// Slot in the number of clients of the proxy in [`ConnectionStage::Handshake`],
+    /// held until the client leaves that stage.
+    handshake_slot: std::sync::Mutex<Option<HandshakeSlot>>,
+    /// Number of clients of the proxy in [`ConnectionStage::Connected`].
+    connected_count: Arc<AtomicUsize>,
+    /// Whether to look for signs of a lost connection when spying datagrams.
+    detect_connection_lost: bool,
+    /// Highest reliable frame index sent by the server, [`u32::MAX`] if none yet.
+    highest_reliable_idx: AtomicU32,
+    /// Highest datagram sequence number sent by the server, [`u32::MAX`] if none yet.
+    server_datagram_seq: AtomicU32,
+    /// Highest datagram sequence number sent by the player, [`u32::MAX`] if none yet.
+    player_datagram_seq: AtomicU32,
+    /// Number of datagrams in a row from the server only carrying retransmitted reliable frames.
+    retransmit_streak: AtomicUsize,
+    /// Reassembler for fragmented frames sent by the player.
+    player_fragments: std::sync::Mutex<FragmentReassembler>,
+    /// Reassembler for fragmented frames sent by the server.
+    server_fragments: std::sync::Mutex<FragmentReassembler>,
+    /// Datagrams that filled the receive buffer, shared with the proxy.
+    truncation_warnings: Arc<TruncationWarnings>,
 
     /// Close notifier.
     close_tx: mpsc::Sender<DisconnectCause>,
@@ -83,6 +193,17 @@ struct RaknetClient {
     close_lock: Semaphore,
 }
 
+/// A slot in the number of clients in [`ConnectionStage::Handshake`], released when dropped.
+struct HandshakeSlot(Arc<AtomicUsize>);
+
+/// Counter of datagrams that filled the receive buffer, so that senders can't flood
+/// the log with warnings about them.
+#[derive(Default)]
+struct TruncationWarnings {
+    /// Datagrams counted since the last warning, with the time of that warning.
+    state: std::sync::Mutex<(u64, Option<Instant>)>,
+}
+
 /// The stage at which a connection is at.
 enum ConnectionStage {
     /// Processing Raknet handshake packets (open connection 1 & 2).
@@ -99,6 +220,9 @@ enum SpyDatagramResult {
     Ignore,
     /// The datagram contains a [`RaknetMessage::DisconnectNotification`].
     Disconnect,
+    /// The datagram contains a [`RaknetMessage::ConnectionLost`], or the server
+    /// has been retransmitting reliable frames the player never acknowledged.
+    ConnectionLost,
 }
 
 /// Data flow direction.
@@ -111,20 +235,89 @@ enum Direction {
 }
 
 /// Why a player disconnected from a server.
-#[derive(Debug, Clone, Copy)]
-enum DisconnectCause {
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectCause {
     /// Found disconnect notification from the client.
     Client,
     /// Found disconnect notification from the server.
     Server,
-    /// Connection timed out.
+    /// Connection timed out, the server stopped sending data.
     Timeout,
+    /// Connection timed out, the player stopped sending data.
+    PlayerTimeout,
+    /// The server stopped answering keepalive pings.
+    ServerUnresponsive,
+    /// The connection was detected as lost.
+    ConnectionLost,
+    /// The server never accepted the handshake of the player.
+    ServerRejected,
+    /// The proxy is shutting down.
+    Shutdown,
     /// An unexpected error occurred.
     Error,
     /// Unknown cause.
     Unknown,
 }
 
+/// Miscellaneous counters of a [`RaknetProxy`].
+#[derive(Debug, Clone)]
+pub struct ProxyStats {
+    /// Number of new connections dropped by the rate limiter.
+    pub rate_limited_handshakes: u64,
+    /// Number of unconnected pings dropped by the rate limiter.
+    pub rate_limited_pings: u64,
+    /// Traffic breakdown per active server.
+    pub traffic: HashMap<SocketAddr, ServerTraffic>,
+    /// Session duration histogram per active server.
+    pub session_durations: HashMap<SocketAddr, [u64; SESSION_DURATION_BUCKETS]>,
+    /// Recent connections per second, per active server.
+    pub connect_rates: HashMap<SocketAddr, f64>,
+    /// Number of joins the server never accepted, per active server.
+    pub rejected_joins: HashMap<SocketAddr, u64>,
+    /// State of the load balancer.
+    pub load_balancer: LoadBalancerStats,
+    /// Number of UDP sockets held open by the proxy (one per client, plus the listening one
+    /// and idle pooled ones).
+    pub open_sockets: usize,
+    /// Number of idle sockets in the socket pool.
+    pub pooled_sockets: usize,
+    /// Approximate memory used by the clients, in bytes.
+    pub clients_memory: usize,
+    /// MOTD information from the last Raknet health check, per active server.
+    pub server_motds: HashMap<SocketAddr, Motd>,
+    /// Time elapsed since the last successful MOTD fetch, per MOTD source.
+    pub motd_last_success: HashMap<String, Option<Duration>>,
+}
+
+/// Traffic forwarded through a backend server.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerTraffic {
+    /// Bytes forwarded to the server.
+    pub bytes_up: u64,
+    /// Bytes forwarded from the server to players.
+    pub bytes_down: u64,
+}
+
+/// Outcome of recovering active connections from a recovery snapshot.
+#[derive(Debug, Clone, Copy)]
+pub struct RecoveryOutcome {
+    /// Number of clients successfully recovered.
+    pub recovered: usize,
+    /// Number of clients in the snapshot.
+    pub total: usize,
+}
+
+impl RecoveryOutcome {
+    /// Percentage of clients successfully recovered.
+    pub fn success_rate(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+        self.recovered as f64 * 100.0 / self.total as f64
+    }
+}
+
 /// Overview of the load of a [`RaknetProy`].
 #[derive(Debug, Clone)]
 pub struct LoadOverview {
@@ -136,52 +329,132 @@ pub struct LoadOverview {
     pub per_server: HashMap<SocketAddr, usize>,
 }
 
-impl RaknetProxy {
-    /// Attempts to bind a proxy server to a UDP socket.
+/// Summary of an active client of a [`RaknetProxy`].
+#[derive(Debug, Clone)]
+pub struct ClientSummary {
+    /// Remote player client address.
+    pub addr: SocketAddr,
+    /// Address of the server the player is proxied to.
+    pub server_addr: SocketAddr,
+    /// Whether the client is past the Raknet handshake.
+    pub connected: bool,
+    /// Time since the client was created.
+    pub age: Duration,
+}
+
+/// Builder of a [`RaknetProxy`], for the options that don't come from config.
+pub struct RaknetProxyBuilder {
+    config_provider: Arc<ConfigProvider>,
+    recovery_snapshot_file: Option<PathBuf>,
+    snapshot_format: SnapshotFormat,
+    observer: Option<Arc<dyn ProxyObserver>>,
+}
+
+impl RaknetProxyBuilder {
+    /// Creates a builder without recovery snapshots nor observer.
     ///
     /// ## Arguments
     ///
-    /// * `in_addr` - Address to bind to for Player <-> Proxy traffic
     /// * `config_provider` - Config provider
-    /// * `recovery_snapshot_file` - Recovery snapshot file
-    pub async fn bind<A: ToSocketAddrs>(
-        in_addr: A,
-        config_provider: Arc<ConfigProvider>,
-        recovery_snapshot_file: PathBuf,
-    ) -> std::io::Result<Arc<Self>> {
-        let in_udp_sock = UdpSocket::bind(in_addr).await?;
+    pub fn new(config_provider: Arc<ConfigProvider>) -> Self {
+        Self {
+            config_provider,
+            recovery_snapshot_file: None,
+            snapshot_format: SnapshotFormat::default(),
+            observer: None,
+        }
+    }
+
+    /// Sets the file recovery snapshots are written to. If [`None`], no snapshot is written.
+    pub fn recovery_snapshot_file(mut self, file: Option<PathBuf>) -> Self {
+        self.recovery_snapshot_file = file;
+        self
+    }
+
+    /// Sets how recovery snapshots are written.
+    pub fn snapshot_format(mut self, format: SnapshotFormat) -> Self {
+        self.snapshot_format = format;
+        self
+    }
+
+    /// Sets the observer notified of what happens in the proxy.
+    pub fn observer(mut self, observer: Option<Arc<dyn ProxyObserver>>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Attempts to bind the proxy server to a UDP socket.
+    ///
+    /// ## Arguments
+    ///
+    /// * `in_addr` - Address to bind to for Player <-> Proxy traffic
+    pub async fn bind<A: ToSocketAddrs>(self, in_addr: A) -> std::io::Result<Arc<RaknetProxy>> {
+        let (socket_options, proxy_bind, socket_pool_size) = {
+            let config = self.config_provider.read().await;
+            (
+                UdpSocketOptions::from_config(&config),
+                config.proxy_bind.clone(),
+                config.socket_pool_size,
+            )
+        };
+        let in_udp_sock = socket::bind_udp(in_addr, &socket_options).await?;
+        let socket_pool = match socket_pool_size {
+            Some(size) => Some(Arc::new(
+                SocketPool::new(proxy_bind, size, &socket_options).await?,
+            )),
+            None => None,
+        };
         let in_bound_port = in_udp_sock.local_addr()?.port();
         let server_uuid = rand::thread_rng().gen();
-        let motd_reflector = Arc::new(MOTDReflector::new(config_provider.clone()));
-        let health_controller = Arc::new(HealthController::new(config_provider.clone()));
+        let motd_reflector = Arc::new(MOTDReflector::new(self.config_provider.clone()));
+        let health_controller = Arc::new(HealthController::new(
+            self.config_provider.clone(),
+            self.observer.clone(),
+        ));
         let load_balancer =
-            LoadBalancer::init(config_provider.clone(), health_controller.clone()).await;
+            LoadBalancer::init(self.config_provider.clone(), health_controller.clone()).await;
         let scheduler = Scheduler::new(
-            config_provider.clone(),
+            self.config_provider.clone(),
             motd_reflector.clone(),
             health_controller.clone(),
         );
-        Ok(Arc::new(Self {
+        Ok(Arc::new(RaknetProxy {
             in_udp_sock: Arc::new(in_udp_sock),
             in_bound_port,
             server_uuid,
-            config_provider,
+            config_provider: self.config_provider,
             clients: Default::default(),
             motd_reflector,
             load_balancer,
             health_controller,
             scheduler,
-            recovery_snapshot_file,
+            draining: AtomicBool::new(false),
+            handshake_limiter: RateLimiter::new(),
+            ping_limiter: RateLimiter::new(),
+            handshake_count: Default::default(),
+            connected_count: Default::default(),
+            socket_pool,
+            truncation_warnings: Default::default(),
+            recovery_snapshot_file: self.recovery_snapshot_file,
+            snapshot_format: self.snapshot_format,
+            snapshot_lock: Mutex::new(()),
+            observer: self.observer,
         }))
     }
+}
 
+impl RaknetProxy {
     /// Recovers active connections from a recovery snapshot.
     ///
     /// ## Arguments
     ///
     /// * `snapshot` - Recovery snapshot
-    pub async fn recover_from_snapshot(&self, snapshot: RaknetProxySnapshot) {
+    pub async fn recover_from_snapshot(&self, snapshot: RaknetProxySnapshot) -> RecoveryOutcome {
         let mut servers: HashMap<SocketAddr, Arc<BackendServer>> = HashMap::new();
+        let mut outcome = RecoveryOutcome {
+            recovered: 0,
+            total: snapshot.clients.len(),
+        };
         for client in snapshot.clients {
             let addr = match SocketAddr::from_str(&client.addr) {
                 Ok(addr) => addr,
@@ -225,6 +498,7 @@ impl RaknetProxy {
                 .new_client(
                     addr,
                     ConnectionStage::Connected,
+                    None,
                     Some(client.proxy_server_bind),
                     Some(server),
                 )
@@ -236,6 +510,7 @@ impl RaknetProxy {
                     err
                 );
             } else {
+                outcome.recovered += 1;
                 log::info!(
                     "Recover player {}. Connected to {}",
                     client.addr,
@@ -244,8 +519,14 @@ impl RaknetProxy {
             }
         }
         for (_, server) in servers {
+            log::debug!(
+                "Server {} has {} recovered clients",
+                server.addr,
+                server.load.load(Ordering::Relaxed)
+            );
             self.health_controller.register_server(server).await;
         }
+        outcome
     }
 
     /// Reloads configuration.
@@ -276,6 +557,100 @@ impl RaknetProxy {
         }
     }
 
+    /// Lists active clients, ordered by address.
+    ///
+    /// ## Arguments
+    ///
+    /// * `offset` - Number of clients to skip
+    /// * `limit` - Maximum number of clients to return
+    ///
+    /// ## Returns
+    ///
+    /// The requested clients and the total number of active clients.
+    pub async fn list_clients(&self, offset: usize, limit: usize) -> (Vec<ClientSummary>, usize) {
+        let clients = self.clients.read().await;
+        let mut addrs = clients.keys().copied().collect::<Vec<_>>();
+        addrs.sort_unstable();
+        let mut page = Vec::new();
+        for addr in addrs.iter().skip(offset).take(limit) {
+            let client = &clients[addr];
+            let connected = matches!(*client.stage.read().await, ConnectionStage::Connected);
+            page.push(ClientSummary {
+                addr: *addr,
+                server_addr: client.server.addr,
+                connected,
+                age: client.created_at.elapsed(),
+            });
+        }
+        (page, clients.len())
+    }
+
+    /// Obtains miscellaneous counters.
+    pub async fn stats(&self) -> ProxyStats {
+        let servers = self.load_balancer.servers().await;
+        let traffic = servers
+            .iter()
+            .map(|server| {
+                let traffic = ServerTraffic {
+                    bytes_up: server.bytes_up.load(Ordering::Relaxed),
+                    bytes_down: server.bytes_down.load(Ordering::Relaxed),
+                };
+                (server.addr, traffic)
+            })
+            .collect();
+        let session_durations = servers
+            .iter()
+            .map(|server| (server.addr, server.session_durations.counts()))
+            .collect();
+        let connect_rates = servers
+            .iter()
+            .map(|server| (server.addr, server.connect_rate.per_second()))
+            .collect();
+        let rejected_joins = servers
+            .iter()
+            .map(|server| (server.addr, server.rejected_joins.load(Ordering::Relaxed)))
+            .collect();
+        let mut server_motds = HashMap::new();
+        for server in servers.iter() {
+            if let Some(motd) = &server.health.read().await.motd {
+                server_motds.insert(server.addr, motd.clone());
+            }
+        }
+        let (client_count, clients_memory) = {
+            let clients = self.clients.read().await;
+            let entry_size =
+                std::mem::size_of::<SocketAddr>() + std::mem::size_of::<Arc<RaknetClient>>();
+            let memory = clients.capacity() * entry_size
+                + clients.len() * std::mem::size_of::<RaknetClient>();
+            (clients.len(), memory)
+        };
+        let pooled_sockets = self
+            .socket_pool
+            .as_ref()
+            .map(|pool| pool.idle_count())
+            .unwrap_or_default();
+        ProxyStats {
+            rate_limited_handshakes: self.handshake_limiter.exceeded_count(),
+            rate_limited_pings: self.ping_limiter.exceeded_count(),
+            traffic,
+            session_durations,
+            connect_rates,
+            rejected_joins,
+            load_balancer: self.load_balancer.stats().await,
+            open_sockets: client_count + 1 + pooled_sockets,
+            pooled_sockets,
+            clients_memory,
+            server_motds,
+            motd_last_success: self.motd_reflector.since_last_success().await,
+        }
+    }
+
+    /// Gets the address of the server the next player would be sent to,
+    /// without affecting load balancing.
+    pub async fn peek_server(&self) -> Option<SocketAddr> {
+        self.load_balancer.peek().await.map(|server| server.addr)
+    }
+
     /// Runs the proxy server.
     ///
     /// If stopped graciously it will return `Ok(())`, otherwise it will return an error.
@@ -286,11 +661,30 @@ impl RaknetProxy {
             self.in_udp_sock.local_addr()?
         );
 
+        let recv_buffer_size = {
+            let config = self.config_provider.read().await;
+            config.recv_buffer_size.unwrap_or(raknet::DEFAULT_MTU)
+        };
         let udp_sock = self.in_udp_sock.clone();
-        let mut buf = [0u8; 1492];
+        // each datagram is split off the shared buffer, which reclaims
+        // its allocation once the previous datagrams have been dropped
+        let mut buf = BytesMut::with_capacity(RECV_BUFFER_DATAGRAMS * recv_buffer_size);
         loop {
-            let (len, addr) = udp_sock.recv_from(&mut buf).await?;
-            let data = Bytes::copy_from_slice(&buf[..len]);
+            buf.reserve(recv_buffer_size);
+            let (len, addr) = udp_sock
+                .recv_buf_from(&mut (&mut buf).limit(recv_buffer_size))
+                .await?;
+            if len == recv_buffer_size {
+                if let Some(count) = self.truncation_warnings.record() {
+                    log::warn!(
+                        "[{}] Datagram filled the {} bytes receive buffer, it may have been truncated ({} since the last warning)",
+                        addr,
+                        recv_buffer_size,
+                        count
+                    );
+                }
+            }
+            let data = buf.split().freeze();
 
             tokio::spawn({
                 let __self = self.clone();
@@ -307,6 +701,54 @@ impl RaknetProxy {
         }
     }
 
+    /// Gracefully shuts down the proxy.
+    ///
+    /// New connections are refused from now on, and active clients are notified of
+    /// the shutdown. Returns once all of them are closed, or when the deadline is reached.
+    ///
+    /// ## Arguments
+    ///
+    /// * `deadline` - Maximum time to wait for clients to disconnect
+    pub async fn shutdown_graceful(&self, deadline: Duration) {
+        self.draining.store(true, Ordering::Release);
+        // a periodic snapshot would bring drained players back on restart
+        self.discard_snapshot().await;
+        let deadline = tokio::time::Instant::now() + deadline;
+        let clients: Vec<Arc<RaknetClient>> = {
+            let clients = self.clients.read().await;
+            clients.values().cloned().collect()
+        };
+        log::info!("Draining {} clients...", clients.len());
+        for client in clients.iter() {
+            let _ = client.close_tx.send(DisconnectCause::Shutdown).await;
+        }
+        if Self::wait_closed(&clients, deadline).await {
+            log::info!("All clients drained");
+        } else {
+            log::warn!("Drain deadline reached, some clients did not close in time");
+        }
+    }
+
+    /// Waits for clients to close.
+    ///
+    /// Returns whether all of them closed before the deadline.
+    ///
+    /// ## Arguments
+    ///
+    /// * `clients` - Clients to wait for
+    /// * `deadline` - Time after which to stop waiting
+    async fn wait_closed(clients: &[Arc<RaknetClient>], deadline: tokio::time::Instant) -> bool {
+        for client in clients.iter() {
+            if tokio::time::timeout_at(deadline, client.close_lock.acquire())
+                .await
+                .is_err()
+            {
+                return false;
+            }
+        }
+        true
+    }
+
     /// Performs a cleanup after the proxy stopped.
     pub async fn cleanup(&self) {
         self.scheduler.stop(true).await;
@@ -334,6 +776,7 @@ impl RaknetProxy {
         }
         let taken_at = SystemTime::now();
         Ok(RaknetProxySnapshot {
+            version: SNAPSHOT_VERSION,
             taken_at,
             config,
             player_proxy_bind,
@@ -342,7 +785,22 @@ impl RaknetProxy {
     }
 
     /// Takes a snapshot of the current proxy state and try to write it to disk.
+    ///
+    /// Does nothing and returns `true` if recovery is disabled.
     pub async fn take_and_write_snapshot(&self) -> bool {
+        let recovery_snapshot_file = match &self.recovery_snapshot_file {
+            Some(recovery_snapshot_file) => recovery_snapshot_file,
+            None => {
+                log::debug!("Recovery is disabled, not taking a snapshot");
+                return true;
+            }
+        };
+        let _guard = self.snapshot_lock.lock().await;
+        if self.draining.load(Ordering::Acquire) {
+            // drained players must not be recovered, the snapshot is discarded
+            log::debug!("Proxy is draining, not taking a snapshot");
+            return true;
+        }
         let snapshot = match self.take_snapshot().await {
             Ok(snapshot) => snapshot,
             Err(err) => {
@@ -350,7 +808,11 @@ impl RaknetProxy {
                 return false;
             }
         };
-        match snapshot::write_snapshot_file(&self.recovery_snapshot_file, &snapshot) {
+        match snapshot::write_snapshot_file(
+            recovery_snapshot_file,
+            &snapshot,
+            &self.snapshot_format,
+        ) {
             Ok(_) => true,
             Err(err) => {
                 log::error!("Could not write proxy state snapshot to disk: {:?}", err);
@@ -359,6 +821,38 @@ impl RaknetProxy {
         }
     }
 
+    /// Removes the recovery snapshot from disk, if recovery is enabled.
+    pub async fn discard_snapshot(&self) {
+        let recovery_snapshot_file = match &self.recovery_snapshot_file {
+            Some(recovery_snapshot_file) => recovery_snapshot_file,
+            None => return,
+        };
+        // waits for a periodic snapshot being written
+        let _guard = self.snapshot_lock.lock().await;
+        if let Err(err) = snapshot::remove_snapshot_file(recovery_snapshot_file) {
+            log::error!("Could not remove recovery snapshot file: {:?}", err);
+        }
+    }
+
+    /// Periodically writes a recovery snapshot to disk, to be able to recover
+    /// from a crash. Stops once the proxy is draining.
+    ///
+    /// ## Arguments
+    ///
+    /// * `period` - Time between two snapshots
+    pub async fn run_periodic_snapshots(&self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            // players leaving while draining would be recovered from a later snapshot
+            if self.draining.load(Ordering::Acquire) {
+                return;
+            }
+            self.take_and_write_snapshot().await;
+        }
+    }
+
     /// Handles incoming data from the UDP socket from the player to the server.
     ///
     /// ## Arguments
@@ -370,6 +864,7 @@ impl RaknetProxy {
             return Ok(());
         }
         let message_type = RaknetMessage::from_u8(data[0]);
+        let is_request = matches!(message_type, Some(RaknetMessage::OpenConnectionRequest1));
         let client = {
             let clients = self.clients.read().await;
             clients.get(&addr).cloned()
@@ -381,14 +876,45 @@ impl RaknetProxy {
                 ),
                 _,
             ) => {
+                let ping_rate_limit = self.config_provider.read().await.ping_rate_limit;
+                if let Some(rate) = ping_rate_limit {
+                    if !self.ping_limiter.check(addr.ip(), rate) {
+                        return Ok(());
+                    }
+                }
                 let mut buf = ReadBuf::new(data);
                 let _ = buf.read_u8()?;
                 self.handle_unconnected_ping(addr, buf).await?;
             }
+            // a new connection request over a connection the server went quiet on
+            // (e.g. the player restarted) is handled as an offline message, replacing it
             (_, Some(client))
-                if matches!(*client.stage.read().await, ConnectionStage::Connected) =>
+                if matches!(*client.stage.read().await, ConnectionStage::Connected)
+                    && (!is_request || client.is_server_active()) =>
             {
+                if is_request {
+                    // the session is still alive, let the player reconcile its state
+                    // rather than starting a second handshake over it
+                    log::debug!(
+                        "[{}] Received a new connection request while already connected",
+                        addr
+                    );
+                    let reply = MessageAlreadyConnected {
+                        server_uuid: self.server_uuid,
+                    };
+                    self.in_udp_sock.send_to(&reply.to_bytes()?, addr).await?;
+                    return Ok(());
+                }
                 if let Err(err) = client.handle_incoming_player(data).await {
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(
+                        client_addr = %client.addr,
+                        server_addr = %client.server.addr,
+                        direction = "player_to_server",
+                        error = ?err,
+                        "Unable to handle UDP datagram message"
+                    );
+                    #[cfg(not(feature = "tracing"))]
                     log::debug!(
                         "{} Unable to handle UDP datagram message: {:?}",
                         client.debug_prefix(Direction::PlayerToServer),
@@ -398,13 +924,60 @@ impl RaknetProxy {
             }
             (Some(message_type), mut client) => {
                 log::trace!("[{}] Received offline message {:?}", addr, message_type);
-                if client.is_none() || message_type.eq(&RaknetMessage::OpenConnectionRequest1) {
+                let new_connection = client.is_none() || is_request;
+                if new_connection {
+                    if self.draining.load(Ordering::Acquire) {
+                        log::trace!("[{}] Refusing new connection while draining", addr);
+                        return Ok(());
+                    }
+                    // checked before anything is sent back, so replies can't be used for amplification
+                    let connection_rate_limit =
+                        self.config_provider.read().await.connection_rate_limit;
+                    if let Some(rate) = connection_rate_limit {
+                        if !self.handshake_limiter.check(addr.ip(), rate) {
+                            log::trace!("[{}] Dropping rate limited new connection", addr);
+                            return Ok(());
+                        }
+                    }
+                }
+                if is_request
+                    && !self
+                        .check_open_connection_request(addr, data.clone())
+                        .await?
+                {
+                    return Ok(());
+                }
+                if new_connection {
+                    // retries (e.g. MTU discovery) replace a client already in the handshake
+                    let max_handshakes = self
+                        .config_provider
+                        .read()
+                        .await
+                        .max_concurrent_handshakes
+                        .filter(|_| client.is_none());
+                    let handshake_slot =
+                        match HandshakeSlot::acquire(&self.handshake_count, max_handshakes) {
+                            Some(slot) => slot,
+                            None => {
+                                log::trace!(
+                                    "[{}] Refusing new connection, too many handshakes in flight",
+                                    addr
+                                );
+                                return Ok(());
+                            }
+                        };
                     if let Some(client) = client {
                         let _ = client.close_tx.send(DisconnectCause::Unknown).await;
                         let _ = client.close_lock.acquire().await;
                     }
                     let new_client = self
-                        .new_client(addr, ConnectionStage::Handshake, None, None)
+                        .new_client(
+                            addr,
+                            ConnectionStage::Handshake,
+                            Some(handshake_slot),
+                            None,
+                            None,
+                        )
                         .await?;
                     client = Some(new_client);
                 }
@@ -415,6 +988,86 @@ impl RaknetProxy {
         Ok(())
     }
 
+    /// Validates an open connection request 1 from a player, rejecting it if
+    /// the requested MTU is below the configured range, or if its Raknet
+    /// protocol version isn't supported. Requests above the range are dropped
+    /// without a reply, as they are MTU discovery probes.
+    ///
+    /// Returns whether the request is valid and should be forwarded.
+    ///
+    /// ## Arguments
+    ///
+    /// * `addr` - Remote player client address
+    /// * `data` - Raw received data
+    async fn check_open_connection_request(
+        &self,
+        addr: SocketAddr,
+        data: Bytes,
+    ) -> anyhow::Result<bool> {
+        let (min_mtu, max_mtu, supported_protocols) = {
+            let config = self.config_provider.read().await;
+            (
+                config.min_mtu,
+                config.max_mtu,
+                config.supported_protocols.clone(),
+            )
+        };
+        if min_mtu.is_none() && max_mtu.is_none() && supported_protocols.is_none() {
+            return Ok(true);
+        }
+        let mut buf = ReadBuf::new(data);
+        let _ = buf.read_u8()?;
+        let request = MessageOpenConnectionRequest1::deserialize(&mut buf)?;
+        // advertise the latest supported version, so the player knows what to expect
+        let preferred_protocol = supported_protocols
+            .as_ref()
+            .and_then(|protocols| protocols.iter().max())
+            .map(|version| ProtocolVersion::from_u8(*version))
+            .unwrap_or(ProtocolVersion::V11);
+        let protocol = request.raknet_protocol.to_u8();
+        if matches!(&supported_protocols, Some(protocols) if !protocols.contains(&protocol)) {
+            log::debug!(
+                "[{}] Rejecting connection with unsupported Raknet protocol version {}",
+                addr,
+                protocol
+            );
+            self.reject_connection(addr, preferred_protocol).await?;
+            return Ok(false);
+        }
+        let mtu = request.mtu_size;
+        if matches!(max_mtu, Some(max_mtu) if mtu > max_mtu) {
+            // clients discover the MTU by retrying with smaller sizes until one gets a reply
+            log::trace!("[{}] Dropping connection request with MTU {}", addr, mtu);
+            return Ok(false);
+        }
+        if matches!(min_mtu, Some(min_mtu) if mtu < min_mtu) {
+            log::debug!("[{}] Rejecting connection with MTU {}", addr, mtu);
+            self.reject_connection(addr, preferred_protocol).await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Replies to a connection attempt with an incompatible protocol version message,
+    /// so the player gets an error instead of waiting for a reply that will never come.
+    ///
+    /// ## Arguments
+    ///
+    /// * `addr` - Remote player client address
+    /// * `preferred_protocol` - Raknet protocol version advertised to the player
+    async fn reject_connection(
+        &self,
+        addr: SocketAddr,
+        preferred_protocol: ProtocolVersion,
+    ) -> anyhow::Result<()> {
+        let reply = MessageIncompatibleProtocolVersion {
+            server_uuid: self.server_uuid,
+            preferred_protocol,
+        };
+        self.in_udp_sock.send_to(&reply.to_bytes()?, addr).await?;
+        Ok(())
+    }
+
     /// Creates and insert a new client.
     /// The caller is responsible for ensuring it would not overwrite an existing client,
     /// otherwise an error will be returned and the client won't be created.
@@ -423,6 +1076,7 @@ impl RaknetProxy {
     ///
     /// * `addr` - Remote player client address
     /// * `stage` - Connection stage. Should be [`ConnectionStage::Handshake`] for new ones
+    /// * `handshake_slot` - Slot of the client in the handshake count, if in [`ConnectionStage::Handshake`]
     /// * `proxy_bind` - Specific Proxy <-> Server bind socket address. If [`None`], the
     ///                  default one will be used
     /// * `server` - Specific backend server. If [`None`], one will be picked
@@ -431,19 +1085,64 @@ impl RaknetProxy {
         &self,
         addr: SocketAddr,
         stage: ConnectionStage,
+        handshake_slot: Option<HandshakeSlot>,
         proxy_bind: Option<String>,
         server: Option<Arc<BackendServer>>,
     ) -> anyhow::Result<Arc<RaknetClient>> {
-        let (proxy_bind, proxy_protocol) = {
+        // a specific bind must be a fresh socket, to be recovered as is
+        let use_pool = proxy_bind.is_none();
+        let (
+            proxy_bind,
+            socket_options,
+            proxy_protocol,
+            recv_buffer_size,
+            timeout,
+            player_timeout,
+            handshake_timeout,
+            keepalive_interval,
+            detect_connection_lost,
+        ) = {
             let config = self.config_provider.read().await;
             (
                 proxy_bind.unwrap_or(config.proxy_bind.clone()),
+                UdpSocketOptions::from_config(&config),
                 config.proxy_protocol.unwrap_or(true),
+                config.recv_buffer_size.unwrap_or(raknet::DEFAULT_MTU),
+                Duration::from_secs(config.client_timeout_secs.unwrap_or(10)),
+                config.player_timeout_secs.map(Duration::from_secs),
+                config.handshake_timeout_secs.map(Duration::from_secs),
+                config.keepalive_interval_secs.map(Duration::from_secs),
+                config.detect_connection_lost.unwrap_or(false),
             )
         };
-        let sock = UdpSocket::bind(proxy_bind).await?;
+        // bound before taking the clients lock, lookups of every datagram would wait on it otherwise
+        let socket_pool = self.socket_pool.clone();
+        let pooled_sock = socket_pool
+            .as_ref()
+            .filter(|_| use_pool)
+            .and_then(|pool| pool.checkout(&proxy_bind));
+        let pooled = pooled_sock.is_some();
+        let sock = match pooled_sock {
+            Some(sock) => sock,
+            None => Arc::new(socket::bind_udp(proxy_bind.as_str(), &socket_options).await?),
+        };
+        // a socket no client ended up owning was never used, it can go back to the pool
+        let checkin = |sock| {
+            if let Some(socket_pool) = socket_pool.as_ref().filter(|_| pooled) {
+                socket_pool.checkin(&proxy_bind, sock);
+            }
+        };
+        let udp_sock_addr = match sock.local_addr() {
+            Ok(udp_sock_addr) => udp_sock_addr,
+            Err(err) => {
+                checkin(sock);
+                return Err(err.into());
+            }
+        };
         let mut clients = self.clients.write().await;
         if clients.contains_key(&addr) {
+            drop(clients);
+            checkin(sock);
             return Err(anyhow::anyhow!(
                 "Failed to maintain state for client {}",
                 addr
@@ -456,26 +1155,86 @@ impl RaknetProxy {
                     log::debug!("[{}] Picked server {}", addr, server.addr);
                     server
                 }
-                None => return Err(anyhow::anyhow!("No server available to proxy this player")),
+                None => {
+                    drop(clients);
+                    checkin(sock);
+                    let reply = self.config_provider.read().await.reply_when_unavailable;
+                    if reply.unwrap_or(true) {
+                        let reply = MessageNoFreeIncomingConnection {
+                            server_uuid: self.server_uuid,
+                        };
+                        self.in_udp_sock.send_to(&reply.to_bytes()?, addr).await?;
+                    }
+                    return Err(anyhow::anyhow!("No server available to proxy this player"));
+                }
             },
         };
+        let (tos, proxy_protocol_version, proxy_protocol_mode) = {
+            let config = self.config_provider.read().await;
+            (
+                config.tos_for(server.addr),
+                config.proxy_protocol_version_for(server.addr),
+                config.proxy_protocol_mode_for(server.addr),
+            )
+        };
+        // a recycled socket may still be marked for its previous server
+        if tos.is_some() || pooled {
+            if let Err(err) = socket::set_tos(&sock, tos.unwrap_or(0)) {
+                log::debug!("[{}] Unable to set ToS on socket: {:?}", addr, err);
+            }
+        }
         let (tx, rx) = mpsc::channel(1);
+        // only counted once nothing can fail, the client task uncounts it when it ends
+        let connected_at = if matches!(stage, ConnectionStage::Connected) {
+            self.connected_count.fetch_add(1, Ordering::AcqRel);
+            Some(Instant::now())
+        } else {
+            None
+        };
         let client = Arc::new(RaknetClient {
             addr,
             server,
             proxy_udp_sock: self.in_udp_sock.clone(),
-            udp_sock_addr: sock.local_addr()?,
+            udp_sock_addr,
             udp_sock: sock,
+            socket_pool,
+            recycle_socket: AtomicBool::new(false),
+            proxy_bind,
             stage: RwLock::new(stage),
+            created_at: Instant::now(),
+            connected_at: std::sync::Mutex::new(connected_at),
+            recv_buffer_size,
+            timeout,
+            player_timeout,
+            handshake_timeout,
+            last_server_activity: std::sync::Mutex::new(Instant::now()),
+            last_player_activity: AtomicU64::new(0),
+            keepalive_interval,
+            unanswered_keepalives: AtomicUsize::new(0),
+            bytes_to_server: AtomicU64::new(0),
+            bytes_to_player: AtomicU64::new(0),
+            observer: self.observer.clone(),
+            handshake_slot: std::sync::Mutex::new(handshake_slot),
+            connected_count: self.connected_count.clone(),
+            detect_connection_lost,
+            highest_reliable_idx: AtomicU32::new(u32::MAX),
+            server_datagram_seq: AtomicU32::new(u32::MAX),
+            player_datagram_seq: AtomicU32::new(u32::MAX),
+            retransmit_streak: AtomicUsize::new(0),
+            player_fragments: std::sync::Mutex::new(FragmentReassembler::new(FRAGMENT_BUFFER_SIZE)),
+            server_fragments: std::sync::Mutex::new(FragmentReassembler::new(FRAGMENT_BUFFER_SIZE)),
+            truncation_warnings: self.truncation_warnings.clone(),
             close_tx: tx,
             close_lock: Semaphore::new(0),
         });
+        // counted right away rather than when the task starts, so that the load of
+        // the servers matches the clients map as soon as it is inserted (e.g. after recovery)
+        client.server.load.fetch_add(1, Ordering::Relaxed);
         clients.insert(addr, client.clone());
-        tokio::spawn({
+        let client_task = {
             let client = client.clone();
             let clients = self.clients.clone();
             async move {
-                client.server.load.fetch_add(1, Ordering::Relaxed);
                 let loop_result = client.run_event_loop(rx).await;
                 let client_count = {
                     let mut clients = clients.write().await;
@@ -485,49 +1244,157 @@ impl RaknetProxy {
                 let was_connected = {
                     let mut w = client.stage.write().await;
                     let was_connected = matches!(*w, ConnectionStage::Connected);
+                    match *w {
+                        ConnectionStage::Handshake => {
+                            client.handshake_slot.lock().unwrap().take();
+                        }
+                        ConnectionStage::Connected => {
+                            client.connected_count.fetch_sub(1, Ordering::AcqRel);
+                        }
+                        _ => {}
+                    }
                     *w = ConnectionStage::Closed;
                     was_connected
                 };
-                client.close_lock.add_permits(1);
-                client.server.load.fetch_sub(1, Ordering::Relaxed);
-                let cause = match loop_result {
+                let (cause, reason) = match loop_result {
                     Ok(cause) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(
+                            client_addr = %client.addr,
+                            server_addr = %client.server.addr,
+                            cause = cause.to_str(),
+                            total = client_count,
+                            "Connection closed"
+                        );
+                        #[cfg(not(feature = "tracing"))]
                         log::debug!(
                             "Connection closed: {} | {} total",
                             client.addr,
                             client_count,
                         );
-                        cause
+                        let reason = match cause {
+                            DisconnectCause::Timeout => Some(format!(
+                                "no data from the server for {} seconds",
+                                client.timeout.as_secs()
+                            )),
+                            DisconnectCause::PlayerTimeout => Some(format!(
+                                "no data from the player for {} seconds",
+                                client.player_timeout.unwrap_or_default().as_secs()
+                            )),
+                            _ => None,
+                        };
+                        (cause, reason)
                     }
                     Err(err) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(
+                            client_addr = %client.addr,
+                            server_addr = %client.server.addr,
+                            error = %err,
+                            total = client_count,
+                            "Connection closed unexpectedly"
+                        );
+                        #[cfg(not(feature = "tracing"))]
                         log::debug!(
                             "Connection closed unexpectedly for {}: {} | {} total",
                             client.addr,
                             err,
                             client_count
                         );
-                        DisconnectCause::Error
+                        (DisconnectCause::Error, Some(err.to_string()))
                     }
                 };
                 if was_connected {
+                    // sent before releasing the close lock, as the process
+                    // may exit right after when shutting down
+                    client.notify_disconnect(cause).await;
+                }
+                client.close_lock.add_permits(1);
+                client.server.load.fetch_sub(1, Ordering::Relaxed);
+                if was_connected {
+                    #[cfg(feature = "tracing")]
+                    tracing::info!(
+                        client_addr = %client.addr,
+                        server_addr = %client.server.addr,
+                        cause = cause.to_str(),
+                        "Player has disconnected"
+                    );
+                    #[cfg(not(feature = "tracing"))]
                     log::info!(
                         "Player {} has disconnected from {} ({})",
                         client.addr,
                         client.server.addr,
                         cause.to_str(),
-                    )
+                    );
+                    // the handshake isn't part of the session
+                    let connected_at = *client.connected_at.lock().unwrap();
+                    if let Some(connected_at) = connected_at {
+                        client
+                            .server
+                            .session_durations
+                            .record(connected_at.elapsed());
+                    }
+                    if let Some(observer) = client.observer.clone() {
+                        let info = client.info();
+                        tokio::spawn(async move {
+                            observer
+                                .on_disconnect(&info, cause, reason.as_deref())
+                                .await
+                        });
+                    }
                 }
+                if matches!(cause, DisconnectCause::ServerRejected) {
+                    log::info!(
+                        "Player {} was not accepted by {} within {} seconds",
+                        client.addr,
+                        client.server.addr,
+                        client.handshake_timeout.unwrap_or_default().as_secs(),
+                    );
+                    client.server.rejected_joins.fetch_add(1, Ordering::Relaxed);
+                }
+                // without a disconnect notification, the server may still hold a session
+                // for the socket address, it can't be handed to another player
+                if matches!(cause, DisconnectCause::Client | DisconnectCause::Server) {
+                    client.recycle_socket.store(true, Ordering::Release);
+                }
+                log::debug!(
+                    "Traffic for {}: {} bytes to server, {} bytes to player",
+                    client.addr,
+                    client.bytes_to_server.load(Ordering::Relaxed),
+                    client.bytes_to_player.load(Ordering::Relaxed),
+                );
             }
-        });
+        };
+        #[cfg(feature = "tracing")]
+        let client_task = tracing::Instrument::instrument(
+            client_task,
+            tracing::info_span!(
+                "client",
+                client_addr = %client.addr,
+                server_addr = %client.server.addr
+            ),
+        );
+        tokio::spawn(client_task);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            client_addr = %client.addr,
+            server_addr = %client.server.addr,
+            proxy_addr = %client.udp_sock_addr,
+            total = clients.len(),
+            "Client initialized"
+        );
+        #[cfg(not(feature = "tracing"))]
         log::debug!(
             "Client initialized: {} <-> {} ({}) | {} total",
             client.addr,
             client.server.addr,
-            client.udp_sock.local_addr()?,
+            client.udp_sock_addr,
             clients.len()
         );
         if proxy_protocol {
-            client.send_haproxy_info().await?;
+            client
+                .send_haproxy_info(proxy_protocol_version, proxy_protocol_mode)
+                .await?;
         }
         Ok(client)
     }
@@ -546,14 +1413,46 @@ impl RaknetProxy {
         let ping = MessageUnconnectedPing::deserialize(&mut buf)?;
 
         let server_uuid = self.server_uuid;
+        let (line1, line2, empty_title, version_name, player_count_source) = {
+            let config = self.config_provider.read().await;
+            (
+                config.backend.motd_line1.clone(),
+                config.backend.motd_line2.clone(),
+                config.backend.motd_empty_title.clone(),
+                config.backend.version_name.clone(),
+                config
+                    .backend
+                    .player_count_source
+                    .unwrap_or(PlayerCountSource::Backend),
+            )
+        };
         let motd_payload = match self.motd_reflector.last_motd().await {
             Some(mut motd) => {
                 motd.server_uuid = server_uuid;
                 motd.port_v4 = self.in_bound_port;
                 motd.port_v6 = motd.port_v4;
+                // empty overrides fall through to the reflected values
+                if let Some(line1) = line1.filter(|line| !line.is_empty()) {
+                    motd.lines[0] = line1;
+                }
+                if let Some(line2) = line2.filter(|line| !line.is_empty()) {
+                    motd.lines[1] = line2;
+                }
+                if let Some(version_name) = version_name.filter(|name| !name.is_empty()) {
+                    motd.version_name = version_name;
+                }
+                // kept up to date on stage changes, walking the clients on every ping is too costly
+                let connected_count = self.connected_count.load(Ordering::Acquire);
+                motd.player_count = match player_count_source {
+                    PlayerCountSource::Backend => motd.player_count,
+                    PlayerCountSource::Proxy => connected_count,
+                    PlayerCountSource::Sum => motd.player_count + connected_count,
+                };
                 if motd.lines[0].is_empty() {
                     // motd reply has no effect with an empty title
-                    motd.lines[0] = "...".into();
+                    motd.lines[0] = empty_title
+                        .filter(|title| !title.is_empty())
+                        .unwrap_or_else(|| "...".into());
                 }
                 motd.encode_payload()
             }
@@ -565,43 +1464,150 @@ impl RaknetProxy {
             server_uuid,
             motd: motd_payload,
         };
-        self.in_udp_sock.send_to(&pong.to_bytes()?, addr).await?;
+        let pong = pong.to_bytes_with_mtu(raknet::DEFAULT_MTU)?;
+        self.in_udp_sock.send_to(&pong, addr).await?;
         Ok(())
     }
 }
 
 impl RaknetClient {
     /// Sends a packet with HAProxy protocol header.
-    async fn send_haproxy_info(&self) -> anyhow::Result<()> {
-        let header = haproxy::Builder::with_addresses(
-            haproxy::Version::Two | haproxy::Command::Proxy,
-            haproxy::Protocol::Datagram,
-            (self.addr, self.proxy_udp_sock.local_addr()?),
-        )
-        .build()?;
+    ///
+    /// ## Arguments
+    ///
+    /// * `version` - Version of the header
+    /// * `mode` - Transport protocol to advertise in the header
+    async fn send_haproxy_info(
+        &self,
+        version: ProxyProtocolVersion,
+        mode: ProxyProtocolMode,
+    ) -> anyhow::Result<()> {
+        let header = proxy_protocol::build_header(
+            version,
+            mode,
+            self.addr,
+            self.proxy_udp_sock.local_addr()?,
+        )?;
         self.udp_sock.send_to(&header, self.server.addr).await?;
         Ok(())
     }
 
+    /// Information about the client, as handed to the observer.
+    fn info(&self) -> ClientInfo {
+        ClientInfo {
+            addr: self.addr,
+            server_addr: self.server.addr,
+        }
+    }
+
+    /// Whether the server recently sent session data, proving the connection is still alive.
+    fn is_server_active(&self) -> bool {
+        self.last_server_activity.lock().unwrap().elapsed() < SERVER_ACTIVITY_WINDOW
+    }
+
+    /// Sends a keepalive ping to the server.
+    ///
+    /// An unconnected ping is used rather than a connected one, as injecting frames
+    /// would desync the sequence numbers of the Raknet session.
+    async fn send_keepalive(&self) -> anyhow::Result<()> {
+        let timestamp = self.created_at.elapsed().as_millis() as i64;
+        let ping = MessageUnconnectedPing {
+            client_uuid: 0,
+            forward_timestamp: timestamp,
+        };
+        self.udp_sock
+            .send_to(&ping.to_bytes()?, self.server.addr)
+            .await?;
+        Ok(())
+    }
+
     /// Runs the client event loop.
     async fn run_event_loop(
         &self,
         mut rx: mpsc::Receiver<DisconnectCause>,
     ) -> anyhow::Result<DisconnectCause> {
-        let mut buf = [0u8; 1492];
-        // 10 seconds without data from the server = force close
-        let timeout = Duration::from_secs(10);
+        // server datagrams are handled one at a time, a single datagram is enough to always reuse it
+        let mut buf = BytesMut::with_capacity(self.recv_buffer_size);
+        let mut deadline = tokio::time::Instant::now() + self.timeout;
+        let mut keepalive = self
+            .keepalive_interval
+            .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+        let mut player_deadline = self.player_deadline();
+        let mut handshake_deadline = self
+            .handshake_timeout
+            .map(|timeout| tokio::time::Instant::from_std(self.created_at + timeout));
         loop {
+            buf.reserve(self.recv_buffer_size);
+            let mut recv_buf = (&mut buf).limit(self.recv_buffer_size);
             tokio::select! {
                 cause = rx.recv() => return Ok(cause.unwrap_or(DisconnectCause::Unknown)),
 
-                res = tokio::time::timeout(timeout, self.udp_sock.recv(&mut buf)) => {
-                    let len = match res {
-                        Ok(res) => res?,
+                _ = async { keepalive.as_mut().unwrap().tick().await }, if keepalive.is_some() => {
+                    if matches!(*self.stage.read().await, ConnectionStage::Handshake) {
+                        // there is no session to keep alive yet
+                        continue;
+                    }
+                    let unanswered = self.unanswered_keepalives.fetch_add(1, Ordering::Relaxed);
+                    if unanswered >= KEEPALIVE_MAX_UNANSWERED {
+                        return Ok(DisconnectCause::ServerUnresponsive);
+                    }
+                    if let Err(err) = self.send_keepalive().await {
+                        log::debug!(
+                            "{} Unable to send keepalive ping: {:?}",
+                            self.debug_prefix(Direction::PlayerToServer),
+                            err
+                        );
+                    }
+                }
+
+                _ = async { tokio::time::sleep_until(player_deadline.unwrap()).await }, if player_deadline.is_some() => {
+                    // the player may have sent data since the deadline was computed
+                    player_deadline = self.player_deadline();
+                    if player_deadline.is_some_and(|deadline| deadline <= tokio::time::Instant::now()) {
+                        return Ok(DisconnectCause::PlayerTimeout);
+                    }
+                }
+
+                _ = async { tokio::time::sleep_until(handshake_deadline.unwrap()).await }, if handshake_deadline.is_some() => {
+                    handshake_deadline = None;
+                    if matches!(*self.stage.read().await, ConnectionStage::Handshake) {
+                        return Ok(DisconnectCause::ServerRejected);
+                    }
+                }
+
+                res = tokio::time::timeout_at(deadline, self.udp_sock.recv_buf(&mut recv_buf)) => {
+                    match res {
+                        Ok(res) => {
+                            if res? == self.recv_buffer_size {
+                                if let Some(count) = self.truncation_warnings.record() {
+                                    log::warn!(
+                                        "{} Datagram filled the {} bytes receive buffer, it may have been truncated ({} since the last warning)",
+                                        self.debug_prefix(Direction::ServerToPlayer),
+                                        self.recv_buffer_size,
+                                        count
+                                    );
+                                }
+                            }
+                        }
                         Err(_) => return Ok(DisconnectCause::Timeout),
-                    };
-                    let data = Bytes::copy_from_slice(&buf[..len]);
+                    }
+                    let data = buf.split().freeze();
+                    // a server that dropped the session may still answer pings,
+                    // only actual session traffic proves it is alive
+                    if !self.is_keepalive_reply(&data) {
+                        deadline = tokio::time::Instant::now() + self.timeout;
+                        *self.last_server_activity.lock().unwrap() = Instant::now();
+                    }
                     if let Err(err) = self.handle_incoming_server(data).await {
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(
+                            client_addr = %self.addr,
+                            server_addr = %self.server.addr,
+                            direction = "server_to_player",
+                            error = ?err,
+                            "Unable to handle UDP datagram message"
+                        );
+                        #[cfg(not(feature = "tracing"))]
                         log::debug!(
                             "{} Unable to handle UDP datagram message: {:?}",
                             self.debug_prefix(Direction::ServerToPlayer),
@@ -613,6 +1619,28 @@ impl RaknetClient {
         }
     }
 
+    /// Checks whether data received from the server answers a keepalive ping.
+    ///
+    /// ## Arguments
+    ///
+    /// * `data` - Raw received data
+    fn is_keepalive_reply(&self, data: &[u8]) -> bool {
+        // the player never sends unconnected pings through here, pongs can only be for keepalives
+        self.keepalive_interval.is_some()
+            && data.first() == Some(&RaknetMessage::UnconnectedPong.to_u8())
+    }
+
+    /// Gets the time at which the connection should be closed if the player
+    /// doesn't send anything in the meantime, if a player timeout is set.
+    fn player_deadline(&self) -> Option<tokio::time::Instant> {
+        let timeout = self.player_timeout?;
+        let last_activity =
+            Duration::from_millis(self.last_player_activity.load(Ordering::Relaxed));
+        Some(tokio::time::Instant::from_std(
+            self.created_at + last_activity + timeout,
+        ))
+    }
+
     /// Handles incoming data from the UDP socket from the server to the player.
     ///
     /// ## Arguments
@@ -622,12 +1650,34 @@ impl RaknetClient {
         if data.is_empty() {
             return Ok(());
         }
+        if self.is_keepalive_reply(&data) {
+            self.unanswered_keepalives.store(0, Ordering::Relaxed);
+            return Ok(());
+        }
         let message_type = RaknetMessage::from_u8(data[0]);
         if matches!(message_type, Some(RaknetMessage::OpenConnectionReply2)) {
             let mut w = self.stage.write().await;
-            if !matches!(*w, ConnectionStage::Connected) {
+            // a closed client has already been accounted for, it must stay closed
+            if matches!(*w, ConnectionStage::Handshake) {
+                self.handshake_slot.lock().unwrap().take();
+                *self.connected_at.lock().unwrap() = Some(Instant::now());
                 *w = ConnectionStage::Connected;
-                log::info!("Player {} has connected to {}", self.addr, self.server.addr)
+                drop(w);
+                self.connected_count.fetch_add(1, Ordering::AcqRel);
+                #[cfg(feature = "tracing")]
+                tracing::info!(
+                    client_addr = %self.addr,
+                    server_addr = %self.server.addr,
+                    "Player has connected"
+                );
+                #[cfg(not(feature = "tracing"))]
+                log::info!("Player {} has connected to {}", self.addr, self.server.addr);
+                self.server.connect_rate.record();
+                if let Some(observer) = self.observer.clone() {
+                    // dispatched off the forwarding path, a slow observer can't stall it
+                    let info = self.info();
+                    tokio::spawn(async move { observer.on_connect(&info).await });
+                }
             }
         }
         if let Some(message_type) = message_type {
@@ -638,15 +1688,22 @@ impl RaknetClient {
             );
         }
         self.forward_to_player(&data).await;
-        if matches!(
-            self.spy_datagram(Direction::ServerToPlayer, data),
-            Ok(SpyDatagramResult::Disconnect)
-        ) {
-            log::debug!(
-                "{} Found disconnect notification in datagram",
-                self.debug_prefix(Direction::ServerToPlayer),
-            );
-            self.close_tx.send(DisconnectCause::Server).await?;
+        match self.spy_datagram(Direction::ServerToPlayer, data) {
+            Ok(SpyDatagramResult::Disconnect) => {
+                log::debug!(
+                    "{} Found disconnect notification in datagram",
+                    self.debug_prefix(Direction::ServerToPlayer),
+                );
+                self.close_tx.send(DisconnectCause::Server).await?;
+            }
+            Ok(SpyDatagramResult::ConnectionLost) => {
+                log::debug!(
+                    "{} Connection to the player seems lost",
+                    self.debug_prefix(Direction::ServerToPlayer),
+                );
+                self.close_tx.send(DisconnectCause::ConnectionLost).await?;
+            }
+            _ => {}
         }
         Ok(())
     }
@@ -658,12 +1715,30 @@ impl RaknetClient {
     /// * `data` - Raw data received from the server
     #[inline]
     async fn forward_to_player(&self, data: &[u8]) {
-        if let Err(err) = self.proxy_udp_sock.send_to(data, self.addr).await {
-            log::debug!(
-                "{} Unable to forward data: {:?}",
-                self.debug_prefix(Direction::ServerToPlayer),
-                err
-            );
+        match self.proxy_udp_sock.send_to(data, self.addr).await {
+            Ok(len) => {
+                self.bytes_to_player
+                    .fetch_add(len as u64, Ordering::Relaxed);
+                self.server
+                    .bytes_down
+                    .fetch_add(len as u64, Ordering::Relaxed);
+            }
+            Err(err) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    client_addr = %self.addr,
+                    server_addr = %self.server.addr,
+                    direction = "server_to_player",
+                    error = ?err,
+                    "Unable to forward data"
+                );
+                #[cfg(not(feature = "tracing"))]
+                log::debug!(
+                    "{} Unable to forward data: {:?}",
+                    self.debug_prefix(Direction::ServerToPlayer),
+                    err
+                );
+            }
         }
     }
 
@@ -676,6 +1751,12 @@ impl RaknetClient {
         if data.is_empty() {
             return Ok(());
         }
+        // the player is still there, retransmissions aren't going unanswered
+        self.retransmit_streak.store(0, Ordering::Relaxed);
+        if self.player_timeout.is_some() {
+            let elapsed = self.created_at.elapsed().as_millis() as u64;
+            self.last_player_activity.store(elapsed, Ordering::Relaxed);
+        }
         if data[0] & 0x80 == 0 {
             log::trace!(
                 "{} Received non-datagram data, with header {:02x}",
@@ -688,15 +1769,22 @@ impl RaknetClient {
             return Ok(());
         }
         self.forward_to_server(&data).await;
-        if matches!(
-            self.spy_datagram(Direction::PlayerToServer, data),
-            Ok(SpyDatagramResult::Disconnect)
-        ) {
-            log::debug!(
-                "{} Found disconnect notification in datagram",
-                self.debug_prefix(Direction::PlayerToServer),
-            );
-            self.close_tx.send(DisconnectCause::Client).await?;
+        match self.spy_datagram(Direction::PlayerToServer, data) {
+            Ok(SpyDatagramResult::Disconnect) => {
+                log::debug!(
+                    "{} Found disconnect notification in datagram",
+                    self.debug_prefix(Direction::PlayerToServer),
+                );
+                self.close_tx.send(DisconnectCause::Client).await?;
+            }
+            Ok(SpyDatagramResult::ConnectionLost) => {
+                log::debug!(
+                    "{} Found connection lost notification in datagram",
+                    self.debug_prefix(Direction::PlayerToServer),
+                );
+                self.close_tx.send(DisconnectCause::ConnectionLost).await?;
+            }
+            _ => {}
         }
         Ok(())
     }
@@ -704,45 +1792,217 @@ impl RaknetClient {
     /// Spies a datagram to look for a disconnect notification.
     ///
     /// Since we are looking for something specific and don't want to incur too much overhead anyway,
-    /// the frames are partially decoded, only non-fragmented frames are read given this is what a disconnect
-    /// notification message will be wrapped into.
+    /// the frames are partially decoded. Fragmented frames are inspected once all their fragments are in,
+    /// this never affects forwarding which happens on the raw datagram.
     /// We don't need to bother with frame (re-)ordering either.
     ///
+    /// If `detect_connection_lost` is enabled, [`RaknetMessage::ConnectionLost`] messages are looked for
+    /// too, and reliable frame indices from the server are tracked to notice bursts of retransmissions.
+    ///
     /// ## Arguments
     ///
     /// * `direction` - Data flow direction
     /// * `data` - Datagram received data
     fn spy_datagram(&self, direction: Direction, data: Bytes) -> anyhow::Result<SpyDatagramResult> {
         let mut buf = ReadBuf::new(data);
-        let _ = buf.read_u8()?; // header flags
-        let _ = buf.read_u24()?; // seq
+        let header = buf.read_u8()?;
+        let seq = buf.read_u24()?;
+        if header & 0x60 == 0 {
+            // not an ACK/NACK, keep track of the sequence so the proxy can send datagrams of its own
+            self.record_datagram_seq(direction, seq);
+        }
+        let track_retransmits =
+            self.detect_connection_lost && matches!(direction, Direction::ServerToPlayer);
+        let mut has_reliable = false;
+        let mut has_new_reliable = false;
         while buf.0.has_remaining() {
             let frame = Frame::deserialize(&mut buf)?;
-            if frame.fragment.is_some() || frame.body.is_empty() {
+            if track_retransmits && frame.reliability.is_reliable() {
+                has_reliable = true;
+                has_new_reliable |= self.record_reliable_idx(frame.frame_idx);
+            }
+            let body = match &frame.fragment {
+                Some(fragment) => match self.reassemble(direction, fragment, frame.body) {
+                    Some(body) => body,
+                    None => continue,
+                },
+                None => frame.body,
+            };
+            if body.is_empty() {
                 continue;
             }
-            if frame.body[0] == raknet::GAME_PACKET_HEADER {
+            if body[0] == raknet::GAME_PACKET_HEADER {
                 // we could spy into game packets to look for a Disconnect packet but it may not really be worth it
                 // what happens currently is that when the client receives a Disconnect packet it closes the connection
                 // and never sends an ACK, so the server tries to send the packet in a loop for a few seconds
                 // it's pretty negligible, I don't think it matters much
                 continue;
             }
-            let message_type = RaknetMessage::from_u8(frame.body[0]);
+            let message_type = RaknetMessage::from_u8(body[0]);
             log::trace!(
                 "{} Frame with message type {:?} ({:02x}) and body size {}",
                 self.debug_prefix(direction),
                 message_type,
-                frame.body[0],
-                frame.body.len(),
+                body[0],
+                body.len(),
             );
             if matches!(message_type, Some(RaknetMessage::DisconnectNotification)) {
                 return Ok(SpyDatagramResult::Disconnect);
             }
+            if self.detect_connection_lost
+                && matches!(message_type, Some(RaknetMessage::ConnectionLost))
+            {
+                return Ok(SpyDatagramResult::ConnectionLost);
+            }
+        }
+        if has_reliable {
+            if has_new_reliable {
+                self.retransmit_streak.store(0, Ordering::Relaxed);
+            } else if self.retransmit_streak.fetch_add(1, Ordering::Relaxed) + 1
+                >= RETRANSMIT_BURST_THRESHOLD
+            {
+                return Ok(SpyDatagramResult::ConnectionLost);
+            }
         }
         Ok(SpyDatagramResult::Ignore)
     }
 
+    /// Adds a fragment to the reassembler of a direction.
+    ///
+    /// Returns the body of the first fragment of the frame once all its fragments have been
+    /// received, which is enough to know the message type.
+    ///
+    /// ## Arguments
+    ///
+    /// * `direction` - Data flow direction
+    /// * `fragment` - Fragment information of the frame
+    /// * `body` - Body of the frame
+    fn reassemble(
+        &self,
+        direction: Direction,
+        fragment: &FrameFragment,
+        body: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let reassembler = match direction {
+            Direction::PlayerToServer => &self.player_fragments,
+            Direction::ServerToPlayer => &self.server_fragments,
+        };
+        let body = reassembler.lock().unwrap().push(fragment, body)?;
+        log::trace!(
+            "{} Received all {} fragments of frame {}",
+            self.debug_prefix(direction),
+            fragment.count,
+            fragment.id
+        );
+        Some(body)
+    }
+
+    /// Records a reliable frame index sent by the server.
+    ///
+    /// Returns whether it is newer than all the ones seen before, i.e. it is not a retransmission.
+    ///
+    /// ## Arguments
+    ///
+    /// * `frame_idx` - Reliable frame index
+    fn record_reliable_idx(&self, frame_idx: u32) -> bool {
+        let highest = self.highest_reliable_idx.load(Ordering::Relaxed);
+        // indices are 24 bits and wrap around
+        let distance = frame_idx.wrapping_sub(highest) & 0xffffff;
+        if highest == u32::MAX || (distance != 0 && distance < 0x800000) {
+            self.highest_reliable_idx
+                .store(frame_idx, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Records a datagram sequence number.
+    ///
+    /// ## Arguments
+    ///
+    /// * `direction` - Data flow direction
+    /// * `seq` - Datagram sequence number
+    fn record_datagram_seq(&self, direction: Direction, seq: u32) {
+        let highest_seq = match direction {
+            Direction::PlayerToServer => &self.player_datagram_seq,
+            Direction::ServerToPlayer => &self.server_datagram_seq,
+        };
+        let highest = highest_seq.load(Ordering::Relaxed);
+        // sequence numbers are 24 bits and wrap around
+        let distance = seq.wrapping_sub(highest) & 0xffffff;
+        if highest == u32::MAX || (distance != 0 && distance < 0x800000) {
+            highest_seq.store(seq, Ordering::Relaxed);
+        }
+    }
+
+    /// Tells the sides that won't otherwise know about it that the connection is closed.
+    ///
+    /// The player is notified when the server can't do it itself, so the client shows
+    /// the disconnection right away instead of waiting to time out. The server is notified
+    /// when the proxy shuts down, so it can free the session.
+    ///
+    /// ## Arguments
+    ///
+    /// * `cause` - Why the connection is closed
+    async fn notify_disconnect(&self, cause: DisconnectCause) {
+        if matches!(
+            cause,
+            DisconnectCause::Timeout
+                | DisconnectCause::ServerUnresponsive
+                | DisconnectCause::Shutdown
+        ) {
+            if let Err(err) = self
+                .send_disconnect_notification(Direction::ServerToPlayer)
+                .await
+            {
+                log::debug!(
+                    "{} Unable to send disconnect notification: {:?}",
+                    self.debug_prefix(Direction::ServerToPlayer),
+                    err
+                );
+            }
+        }
+        if matches!(cause, DisconnectCause::Shutdown) {
+            if let Err(err) = self
+                .send_disconnect_notification(Direction::PlayerToServer)
+                .await
+            {
+                log::debug!(
+                    "{} Unable to send disconnect notification: {:?}",
+                    self.debug_prefix(Direction::PlayerToServer),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Sends a disconnect notification on behalf of one side of the connection.
+    ///
+    /// The datagram uses the sequence number following the last one sent by that side,
+    /// which desyncs the session, so it must only be sent when the connection is being closed.
+    ///
+    /// ## Arguments
+    ///
+    /// * `direction` - Data flow direction, from the side the notification is sent on behalf of
+    async fn send_disconnect_notification(&self, direction: Direction) -> anyhow::Result<()> {
+        match direction {
+            Direction::ServerToPlayer => {
+                let datagram = disconnect_notification_datagram(
+                    self.server_datagram_seq.load(Ordering::Relaxed),
+                )?;
+                self.proxy_udp_sock.send_to(&datagram, self.addr).await?;
+            }
+            Direction::PlayerToServer => {
+                let datagram = disconnect_notification_datagram(
+                    self.player_datagram_seq.load(Ordering::Relaxed),
+                )?;
+                self.udp_sock.send_to(&datagram, self.server.addr).await?;
+            }
+        }
+        Ok(())
+    }
+
     /// Forwards data received from the player to the server.
     ///
     /// ## Arguments
@@ -750,12 +2010,30 @@ impl RaknetClient {
     /// * `data` - Raw data received from the player
     #[inline]
     async fn forward_to_server(&self, data: &[u8]) {
-        if let Err(err) = self.udp_sock.send_to(data, self.server.addr).await {
-            log::debug!(
-                "{} Unable to forward data: {:?}",
-                self.debug_prefix(Direction::PlayerToServer),
-                err
-            );
+        match self.udp_sock.send_to(data, self.server.addr).await {
+            Ok(len) => {
+                self.bytes_to_server
+                    .fetch_add(len as u64, Ordering::Relaxed);
+                self.server
+                    .bytes_up
+                    .fetch_add(len as u64, Ordering::Relaxed);
+            }
+            Err(err) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    client_addr = %self.addr,
+                    server_addr = %self.server.addr,
+                    direction = "player_to_server",
+                    error = ?err,
+                    "Unable to forward data"
+                );
+                #[cfg(not(feature = "tracing"))]
+                log::debug!(
+                    "{} Unable to forward data: {:?}",
+                    self.debug_prefix(Direction::PlayerToServer),
+                    err
+                );
+            }
         }
     }
 
@@ -778,6 +2056,57 @@ impl RaknetClient {
     }
 }
 
+impl Drop for RaknetClient {
+    fn drop(&mut self) {
+        // only now that nothing holds the client, no datagram can go through the socket for it
+        if let Some(socket_pool) = &self.socket_pool {
+            if *self.recycle_socket.get_mut() {
+                socket_pool.checkin(&self.proxy_bind, self.udp_sock.clone());
+            }
+        }
+    }
+}
+
+impl HandshakeSlot {
+    /// Takes a slot, unless there already are as many as the limit.
+    ///
+    /// ## Arguments
+    ///
+    /// * `count` - Number of clients in [`ConnectionStage::Handshake`]
+    /// * `limit` - Maximum number of clients in that stage, if limited
+    fn acquire(count: &Arc<AtomicUsize>, limit: Option<usize>) -> Option<Self> {
+        count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| match limit {
+                Some(limit) if count >= limit => None,
+                _ => Some(count + 1),
+            })
+            .ok()?;
+        Some(Self(count.clone()))
+    }
+}
+
+impl Drop for HandshakeSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl TruncationWarnings {
+    /// Counts a datagram that filled the receive buffer.
+    ///
+    /// Returns the number of datagrams counted since the last warning if one should be
+    /// logged, at most once per [`TRUNCATION_WARN_INTERVAL`].
+    fn record(&self) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        state.0 += 1;
+        if matches!(state.1, Some(warned_at) if warned_at.elapsed() < TRUNCATION_WARN_INTERVAL) {
+            return None;
+        }
+        state.1 = Some(Instant::now());
+        Some(std::mem::take(&mut state.0))
+    }
+}
+
 impl DisconnectCause {
     pub fn to_str(self) -> &'static str {
         match self {
@@ -788,8 +2117,514 @@ impl DisconnectCause {
             Self::Client => "normal",
             Self::Server => "server",
             Self::Timeout => "timeout",
+            Self::PlayerTimeout => "player timeout",
+            Self::ServerUnresponsive => "server unresponsive",
+            Self::ConnectionLost => "connection lost",
+            Self::ServerRejected => "server rejected",
+            Self::Shutdown => "proxy shutdown",
             Self::Error => "unexpected error",
             Self::Unknown => "unknown",
         }
     }
 }
+
+/// Builds a datagram holding a single disconnect notification.
+///
+/// ## Arguments
+///
+/// * `last_seq` - Last datagram sequence number sent by the side the datagram is sent on behalf of,
+///                [`u32::MAX`] if none
+fn disconnect_notification_datagram(last_seq: u32) -> Result<Vec<u8>, MessageError> {
+    let seq = match last_seq {
+        u32::MAX => 0,
+        last_seq => last_seq.wrapping_add(1) & 0xffffff,
+    };
+    let frame = Frame {
+        reliability: Reliability::Unreliable,
+        frame_idx: 0,
+        seq: 0,
+        order_idx: 0,
+        fragment: None,
+        body: vec![RaknetMessage::DisconnectNotification.to_u8()],
+    };
+    let mut buf = WriteBuf::new();
+    buf.write_u8(0x84)?; // valid datagram, needs B&AS
+    buf.write_u24(seq)?;
+    frame.serialize(&mut buf)?;
+    Ok(buf.0.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RootConfig;
+
+    /// Builds a config proxying to the given servers, without the PROXY protocol.
+    fn test_config(servers: &[SocketAddr], extra: &str) -> RootConfig {
+        let servers = servers
+            .iter()
+            .map(|addr| format!("{{ address = \"{}\" }}", addr))
+            .collect::<Vec<_>>()
+            .join(", ");
+        toml::from_str(&format!(
+            r#"
+bind_address = "127.0.0.1:0"
+proxy_bind = "127.0.0.1:0"
+proxy_protocol = false
+{}
+
+[backend]
+health_check_rate = 60
+motd_refresh_rate = 60
+servers = [{}]
+"#,
+            extra, servers
+        ))
+        .unwrap()
+    }
+
+    async fn test_proxy(config: RootConfig) -> Arc<RaknetProxy> {
+        let config_provider = Arc::new(ConfigProvider::new(PathBuf::new(), config));
+        RaknetProxyBuilder::new(config_provider)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap()
+    }
+
+    async fn test_socket() -> (UdpSocket, SocketAddr) {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        (sock, addr)
+    }
+
+    /// Receives a datagram, if one arrives shortly.
+    async fn recv_datagram(sock: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = vec![0u8; 2048];
+        match tokio::time::timeout(Duration::from_millis(200), sock.recv(&mut buf)).await {
+            Ok(Ok(len)) => {
+                buf.truncate(len);
+                Some(buf)
+            }
+            _ => None,
+        }
+    }
+
+    fn open_connection_request(mtu_size: u16) -> Bytes {
+        let request = MessageOpenConnectionRequest1 {
+            raknet_protocol: ProtocolVersion::V11,
+            mtu_size,
+        };
+        Bytes::from(request.to_bytes().unwrap())
+    }
+
+    /// Connects a player through the proxy, with the server accepting the connection.
+    async fn connect_player(
+        proxy: &RaknetProxy,
+        player: &UdpSocket,
+        player_addr: SocketAddr,
+        server: &UdpSocket,
+    ) -> Arc<RaknetClient> {
+        proxy
+            .handle_recv(player_addr, open_connection_request(1200))
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 2048];
+        let (_, proxy_addr) =
+            tokio::time::timeout(Duration::from_secs(1), server.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        let reply = [RaknetMessage::OpenConnectionReply2.to_u8()];
+        server.send_to(&reply, proxy_addr).await.unwrap();
+        assert_eq!(recv_datagram(player).await, Some(reply.to_vec()));
+        let client = proxy.clients.read().await[&player_addr].clone();
+        assert!(matches!(
+            *client.stage.read().await,
+            ConnectionStage::Connected
+        ));
+        client
+    }
+
+    fn is_disconnect_notification(datagram: &[u8]) -> bool {
+        datagram.first() == Some(&0x84)
+            && datagram.last() == Some(&RaknetMessage::DisconnectNotification.to_u8())
+    }
+
+    #[tokio::test]
+    async fn drain_notifies_then_waits_for_clients() {
+        let (server, server_addr) = test_socket().await;
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[server_addr], "")).await;
+        let client = connect_player(&proxy, &player, player_addr, &server).await;
+
+        let drain = tokio::spawn({
+            let proxy = proxy.clone();
+            async move { proxy.shutdown_graceful(Duration::from_secs(30)).await }
+        });
+
+        // connected players are notified right away
+        assert!(is_disconnect_notification(
+            &recv_datagram(&player).await.unwrap()
+        ));
+        assert!(is_disconnect_notification(
+            &recv_datagram(&server).await.unwrap()
+        ));
+
+        // new connections are refused while draining
+        let (_, other_addr) = test_socket().await;
+        proxy
+            .handle_recv(other_addr, open_connection_request(1200))
+            .await
+            .unwrap();
+        assert!(!proxy.clients.read().await.contains_key(&other_addr));
+        assert_eq!(recv_datagram(&server).await, None);
+
+        // the drain ends once sessions are closed, well before the deadline
+        tokio::time::timeout(Duration::from_secs(3), drain)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            *client.stage.read().await,
+            ConnectionStage::Closed
+        ));
+        assert!(proxy.clients.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drain_returns_once_players_left() {
+        let (server, server_addr) = test_socket().await;
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[server_addr], "")).await;
+        let client = connect_player(&proxy, &player, player_addr, &server).await;
+
+        let drain = tokio::spawn({
+            let proxy = proxy.clone();
+            async move { proxy.shutdown_graceful(Duration::from_secs(30)).await }
+        });
+        client.close_tx.send(DisconnectCause::Client).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(3), drain)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(proxy.clients.read().await.is_empty());
+    }
+
+    /// Observer whose callbacks never complete.
+    struct StalledObserver;
+
+    impl ProxyObserver for StalledObserver {
+        fn on_connect<'a>(&'a self, _client: &'a ClientInfo) -> crate::events::ObserverFuture<'a> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_observer_does_not_stall_forwarding() {
+        let (server, server_addr) = test_socket().await;
+        let (player, player_addr) = test_socket().await;
+        let config_provider = Arc::new(ConfigProvider::new(
+            PathBuf::new(),
+            test_config(&[server_addr], ""),
+        ));
+        let proxy = RaknetProxyBuilder::new(config_provider)
+            .observer(Some(Arc::new(StalledObserver)))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        // the reply accepting the connection is still forwarded to the player
+        connect_player(&proxy, &player, player_addr, &server).await;
+    }
+
+    #[tokio::test]
+    async fn caps_handshakes_of_new_addresses() {
+        let (server, server_addr) = test_socket().await;
+        let (player, player_addr) = test_socket().await;
+        let (_other, other_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[server_addr], "max_concurrent_handshakes = 1")).await;
+        let request = open_connection_request(1200);
+
+        proxy
+            .handle_recv(player_addr, request.clone())
+            .await
+            .unwrap();
+        assert_eq!(recv_datagram(&server).await, Some(request.to_vec()));
+        proxy
+            .handle_recv(other_addr, request.clone())
+            .await
+            .unwrap();
+        assert!(!proxy.clients.read().await.contains_key(&other_addr));
+        assert_eq!(recv_datagram(&server).await, None);
+
+        // retries from a client already in the handshake go through
+        proxy
+            .handle_recv(player_addr, request.clone())
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 2048];
+        let (_, proxy_addr) =
+            tokio::time::timeout(Duration::from_secs(1), server.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(proxy.handshake_count.load(Ordering::Acquire), 1);
+
+        // a connected player frees its slot
+        let reply = [RaknetMessage::OpenConnectionReply2.to_u8()];
+        server.send_to(&reply, proxy_addr).await.unwrap();
+        assert_eq!(recv_datagram(&player).await, Some(reply.to_vec()));
+        assert_eq!(proxy.handshake_count.load(Ordering::Acquire), 0);
+        proxy
+            .handle_recv(other_addr, request.clone())
+            .await
+            .unwrap();
+        assert!(proxy.clients.read().await.contains_key(&other_addr));
+    }
+
+    #[tokio::test]
+    async fn recycles_socket_once_client_is_dropped() {
+        let (server, server_addr) = test_socket().await;
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[server_addr], "socket_pool_size = 1")).await;
+        let socket_pool = proxy.socket_pool.clone().unwrap();
+        let client = connect_player(&proxy, &player, player_addr, &server).await;
+        assert_eq!(socket_pool.idle_count(), 0);
+
+        client.close_tx.send(DisconnectCause::Client).await.unwrap();
+        let _ = client.close_lock.acquire().await.unwrap();
+        // still held, as if a datagram from the player was being handled
+        assert_eq!(socket_pool.idle_count(), 0);
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while socket_pool.idle_count() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_pooled_socket_when_client_creation_fails() {
+        let (_player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[], "socket_pool_size = 1")).await;
+        let socket_pool = proxy.socket_pool.clone().unwrap();
+
+        assert!(proxy
+            .handle_recv(player_addr, open_connection_request(1200))
+            .await
+            .is_err());
+        assert!(proxy.clients.read().await.is_empty());
+        assert_eq!(socket_pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn truncation_warnings_are_throttled() {
+        let warnings = TruncationWarnings::default();
+        assert_eq!(warnings.record(), Some(1));
+        for _ in 0..100 {
+            assert_eq!(warnings.record(), None);
+        }
+
+        warnings.state.lock().unwrap().1 = Instant::now().checked_sub(TRUNCATION_WARN_INTERVAL * 2);
+        assert_eq!(warnings.record(), Some(101));
+        assert_eq!(warnings.record(), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_pooled_socket_when_insert_loses_race() {
+        let (server, server_addr) = test_socket().await;
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[server_addr], "socket_pool_size = 2")).await;
+        let socket_pool = proxy.socket_pool.clone().unwrap();
+        connect_player(&proxy, &player, player_addr, &server).await;
+        assert_eq!(socket_pool.idle_count(), 1);
+
+        assert!(proxy
+            .new_client(player_addr, ConnectionStage::Handshake, None, None, None)
+            .await
+            .is_err());
+        assert_eq!(socket_pool.idle_count(), 1);
+        assert_eq!(proxy.clients.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn releases_handshake_slot_when_client_creation_fails() {
+        let (_player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[], "max_concurrent_handshakes = 1")).await;
+
+        for _ in 0..2 {
+            assert!(proxy
+                .handle_recv(player_addr, open_connection_request(1200))
+                .await
+                .is_err());
+            assert_eq!(proxy.handshake_count.load(Ordering::Acquire), 0);
+            assert_eq!(proxy.connected_count.load(Ordering::Acquire), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn session_starts_once_connected() {
+        let (server, server_addr) = test_socket().await;
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[server_addr], "")).await;
+        let client = connect_player(&proxy, &player, player_addr, &server).await;
+        let connected_at = client.connected_at.lock().unwrap().unwrap();
+        assert!(connected_at > client.created_at);
+
+        client.close_tx.send(DisconnectCause::Client).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while client.server.session_durations.counts()[0] == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn new_request_while_connected() {
+        let (server, server_addr) = test_socket().await;
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[server_addr], "")).await;
+        let client = connect_player(&proxy, &player, player_addr, &server).await;
+        let request = open_connection_request(1200);
+
+        // the server is still sending data, the session is kept
+        proxy
+            .handle_recv(player_addr, request.clone())
+            .await
+            .unwrap();
+        let reply = recv_datagram(&player).await.unwrap();
+        assert_eq!(reply[0], RaknetMessage::AlreadyConnected.to_u8());
+        assert_eq!(recv_datagram(&server).await, None);
+        assert!(Arc::ptr_eq(
+            &proxy.clients.read().await[&player_addr],
+            &client
+        ));
+
+        // the server went quiet, the stale session is replaced
+        *client.last_server_activity.lock().unwrap() = Instant::now() - SERVER_ACTIVITY_WINDOW * 2;
+        proxy
+            .handle_recv(player_addr, request.clone())
+            .await
+            .unwrap();
+        assert_eq!(recv_datagram(&server).await, Some(request.to_vec()));
+        assert!(matches!(
+            *client.stage.read().await,
+            ConnectionStage::Closed
+        ));
+        let new_client = proxy.clients.read().await[&player_addr].clone();
+        assert!(!Arc::ptr_eq(&new_client, &client));
+        assert!(matches!(
+            *new_client.stage.read().await,
+            ConnectionStage::Handshake
+        ));
+    }
+
+    #[tokio::test]
+    async fn drops_oversized_mtu_probe() {
+        let (server, server_addr) = test_socket().await;
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[server_addr], "max_mtu = 1200")).await;
+
+        proxy
+            .handle_recv(player_addr, open_connection_request(1400))
+            .await
+            .unwrap();
+        assert_eq!(recv_datagram(&player).await, None);
+        assert_eq!(recv_datagram(&server).await, None);
+        assert!(proxy.clients.read().await.is_empty());
+
+        let request = open_connection_request(1200);
+        proxy
+            .handle_recv(player_addr, request.clone())
+            .await
+            .unwrap();
+        assert!(proxy.clients.read().await.contains_key(&player_addr));
+        assert_eq!(recv_datagram(&server).await, Some(request.to_vec()));
+    }
+
+    #[tokio::test]
+    async fn rejects_undersized_mtu() {
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[], "min_mtu = 576")).await;
+
+        proxy
+            .handle_recv(player_addr, open_connection_request(500))
+            .await
+            .unwrap();
+        let reply = recv_datagram(&player).await.unwrap();
+        assert_eq!(reply[0], RaknetMessage::IncompatibleProtocolVersion.to_u8());
+        assert!(proxy.clients.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replies_when_no_server_is_available() {
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[], "")).await;
+
+        assert!(proxy
+            .handle_recv(player_addr, open_connection_request(1200))
+            .await
+            .is_err());
+        let mut expected = vec![RaknetMessage::NoFreeIncomingConnection.to_u8()];
+        expected.extend_from_slice(&raknet::MAGIC);
+        expected.extend_from_slice(&proxy.server_uuid.to_be_bytes());
+        assert_eq!(recv_datagram(&player).await, Some(expected));
+        assert!(proxy.clients.read().await.is_empty());
+
+        let proxy = test_proxy(test_config(&[], "reply_when_unavailable = false")).await;
+        assert!(proxy
+            .handle_recv(player_addr, open_connection_request(1200))
+            .await
+            .is_err());
+        assert_eq!(recv_datagram(&player).await, None);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_before_replying() {
+        let (player, player_addr) = test_socket().await;
+        let proxy = test_proxy(test_config(&[], "min_mtu = 576\nconnection_rate_limit = 1")).await;
+
+        for _ in 0..2 {
+            proxy
+                .handle_recv(player_addr, open_connection_request(500))
+                .await
+                .unwrap();
+        }
+        assert!(recv_datagram(&player).await.is_some());
+        assert_eq!(recv_datagram(&player).await, None);
+    }
+
+    #[test]
+    fn disconnect_notification_follows_last_seq() {
+        assert_eq!(
+            disconnect_notification_datagram(u32::MAX).unwrap(),
+            vec![0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x15]
+        );
+        assert_eq!(
+            disconnect_notification_datagram(0x010203).unwrap(),
+            vec![0x84, 0x04, 0x02, 0x01, 0x00, 0x00, 0x08, 0x15]
+        );
+        assert_eq!(
+            disconnect_notification_datagram(0xffffff).unwrap()[1..4],
+            [0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn disconnect_notification_decodes() {
+        let datagram = disconnect_notification_datagram(41).unwrap();
+        let mut buf = ReadBuf::new(Bytes::from(datagram));
+        assert_eq!(buf.read_u8().unwrap(), 0x84);
+        assert_eq!(buf.read_u24().unwrap(), 42);
+        let frame = Frame::deserialize(&mut buf).unwrap();
+        assert_eq!(frame.reliability, Reliability::Unreliable);
+        assert_eq!(
+            frame.body,
+            vec![RaknetMessage::DisconnectNotification.to_u8()]
+        );
+        assert!(!buf.0.has_remaining());
+    }
+}
@@ -0,0 +1,61 @@
+use std::net::SocketAddr;
+
+use ppp::v2 as haproxy;
+
+use crate::config::{ProxyProtocolMode, ProxyProtocolVersion};
+
+/// Builds a HAProxy PROXY protocol header.
+///
+/// Version 1 can only describe TCP connections, `mode` is ignored for it.
+///
+/// ## Arguments
+///
+/// * `version` - Version of the protocol
+/// * `mode` - Transport protocol to advertise
+/// * `source` - Address of the client
+/// * `destination` - Address the client connected to
+pub fn build_header(
+    version: ProxyProtocolVersion,
+    mode: ProxyProtocolMode,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> anyhow::Result<Vec<u8>> {
+    match version {
+        ProxyProtocolVersion::V1 => Ok(v1_header(source, destination).into_bytes()),
+        ProxyProtocolVersion::V2 => {
+            let protocol = match mode {
+                ProxyProtocolMode::Udp => haproxy::Protocol::Datagram,
+                ProxyProtocolMode::Tcp => haproxy::Protocol::Stream,
+            };
+            let header = haproxy::Builder::with_addresses(
+                haproxy::Version::Two | haproxy::Command::Proxy,
+                protocol,
+                (source, destination),
+            )
+            .build()?;
+            Ok(header)
+        }
+    }
+}
+
+/// Formats a human-readable (version 1) PROXY protocol header.
+fn v1_header(source: SocketAddr, destination: SocketAddr) -> String {
+    match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        ),
+        (SocketAddr::V6(source), SocketAddr::V6(destination)) => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        ),
+        // version 1 can't describe mixed address families
+        _ => "PROXY UNKNOWN\r\n".to_owned(),
+    }
+}
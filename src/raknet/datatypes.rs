@@ -12,6 +12,9 @@ macro_rules! read_guard {
     };
 }
 
+/// Default maximum length, in bytes, of a single string or bytes read.
+pub const DEFAULT_MAX_READ_LEN: usize = 8192;
+
 /// Alias type for a u24 to make things clearer. Not an actual u24!
 #[allow(non_camel_case_types)]
 pub type u24 = u32;
@@ -26,6 +29,8 @@ pub enum BufError {
     InvalidString,
     /// Invalid socket address
     InvalidAdrress,
+    /// A string or bytes read exceeds the maximum length
+    TooLarge,
 }
 
 impl From<BufError> for anyhow::Error {
@@ -34,15 +39,17 @@ impl From<BufError> for anyhow::Error {
     }
 }
 
+/// Buffer to read from. The second field is the maximum length of a single
+/// string or bytes read, to avoid large allocations from crafted lengths.
 #[derive(Clone, Debug)]
-pub struct ReadBuf(pub Bytes);
+pub struct ReadBuf(pub Bytes, usize);
 
 #[derive(Clone, Debug)]
 pub struct WriteBuf(pub BytesMut);
 
 impl ReadBuf {
     pub fn new(bytes: Bytes) -> Self {
-        Self(bytes)
+        Self(bytes, DEFAULT_MAX_READ_LEN)
     }
 }
 
@@ -54,18 +61,24 @@ impl WriteBuf {
 
 impl From<Vec<u8>> for ReadBuf {
     fn from(val: Vec<u8>) -> Self {
-        ReadBuf(Bytes::from(val))
+        ReadBuf::new(Bytes::from(val))
     }
 }
 
 impl From<&[u8]> for ReadBuf {
     fn from(val: &[u8]) -> Self {
-        ReadBuf(Bytes::copy_from_slice(val))
+        ReadBuf::new(Bytes::copy_from_slice(val))
     }
 }
 
 #[allow(dead_code)]
 impl ReadBuf {
+    /// Sets the maximum length of a single string or bytes read.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.1 = max_len;
+        self
+    }
+
     pub fn read_u8(&mut self) -> Result<u8, BufError> {
         read_guard!(self, 1);
         Ok(self.0.get_u8())
@@ -117,6 +130,9 @@ impl ReadBuf {
     pub fn read_str(&mut self) -> Result<String, BufError> {
         read_guard!(self, 2);
         let len = self.0.get_u16() as usize;
+        if len > self.1 {
+            return Err(BufError::TooLarge);
+        }
         read_guard!(self, len);
         let mut bytes = vec![0u8; len];
         self.0.copy_to_slice(&mut bytes);
@@ -153,6 +169,9 @@ impl ReadBuf {
     }
 
     pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), BufError> {
+        if buf.len() > self.1 {
+            return Err(BufError::TooLarge);
+        }
         read_guard!(self, buf.len());
         self.0.copy_to_slice(buf);
         Ok(())
@@ -235,3 +254,39 @@ impl WriteBuf {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_len_is_capped() {
+        let mut buf = WriteBuf::new();
+        buf.write_str("trakt proxy").unwrap();
+        let bytes = buf.0.freeze();
+
+        let mut buf = ReadBuf::new(bytes.clone()).with_max_len(4);
+        assert!(matches!(buf.read_str(), Err(BufError::TooLarge)));
+        let mut buf = ReadBuf::new(bytes.clone()).with_max_len(11);
+        assert_eq!(buf.read_str().unwrap(), "trakt proxy");
+
+        let mut buf = ReadBuf::new(bytes).with_max_len(4);
+        let mut dest = [0u8; 5];
+        assert!(matches!(buf.read_bytes(&mut dest), Err(BufError::TooLarge)));
+        let mut dest = [0u8; 4];
+        buf.read_bytes(&mut dest).unwrap();
+        assert_eq!(dest, [0x00, 0x0b, b't', b'r']);
+    }
+
+    #[test]
+    fn oversized_length_prefix() {
+        // declares the largest string, followed by only a few bytes
+        let bytes = Bytes::from_static(&[0xff, 0xff, b'a', b'b']);
+        let mut buf = ReadBuf::new(bytes.clone());
+        assert!(matches!(buf.read_str(), Err(BufError::TooLarge)));
+
+        // within the cap, it fails on the missing data instead
+        let mut buf = ReadBuf::new(bytes).with_max_len(u16::MAX as usize);
+        assert!(matches!(buf.read_str(), Err(BufError::NotEnoughData)));
+    }
+}
@@ -1,10 +1,13 @@
 use bytes::Buf;
 
-use super::datatypes::{ReadBuf, WriteBuf};
+use super::datatypes::{BufError, ReadBuf, WriteBuf};
 use super::message::{Message, MessageError};
 
 const FLAG_FRAGMENTED: u8 = 0x10;
 
+/// Maximum size of a frame body, as its length in bits is encoded on 16 bits.
+const MAX_BODY_SIZE: usize = u16::MAX as usize >> 3;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Reliability {
     Unreliable,
@@ -86,6 +89,10 @@ impl Message for Frame {
             header |= FLAG_FRAGMENTED;
         }
         buf.write_u8(header)?;
+        // the length is encoded in bits
+        if self.body.len() > MAX_BODY_SIZE {
+            return Err(MessageError::BodyTooLarge(self.body.len()));
+        }
         buf.write_u16((self.body.len() << 3) as u16)?;
         if self.reliability.is_reliable() {
             buf.write_u24(self.frame_idx)?;
@@ -129,8 +136,7 @@ impl Message for Frame {
         };
         let order_idx = if reliability.is_ordered() {
             let order_idx = buf.read_u24()?;
-            // skip order channel
-            buf.0.advance(1);
+            let _ = buf.read_u8()?; // order channel
             order_idx
         } else {
             0
@@ -146,6 +152,9 @@ impl Message for Frame {
             None
         };
 
+        if body_len > buf.0.remaining() {
+            return Err(BufError::NotEnoughData.into());
+        }
         let mut body = vec![0u8; body_len];
         buf.read_bytes(&mut body)?;
 
@@ -159,3 +168,111 @@ impl Message for Frame {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn frame(reliability: Reliability, fragment: Option<FrameFragment>) -> Frame {
+        Frame {
+            reliability,
+            frame_idx: if reliability.is_reliable() {
+                0x010203
+            } else {
+                0
+            },
+            seq: if reliability.is_sequenced() {
+                0x040506
+            } else {
+                0
+            },
+            order_idx: if reliability.is_ordered() {
+                0x070809
+            } else {
+                0
+            },
+            fragment,
+            body: vec![0xfe, 1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let reliabilities = [
+            Reliability::Unreliable,
+            Reliability::UnreliableSequenced,
+            Reliability::Reliable,
+            Reliability::ReliableOrdered,
+            Reliability::ReliableSequenced,
+        ];
+        let fragments = [
+            None,
+            Some(FrameFragment {
+                count: 3,
+                index: 2,
+                id: 7,
+            }),
+        ];
+        for reliability in reliabilities {
+            for fragment in fragments.iter().cloned() {
+                let expected = frame(reliability, fragment);
+                let bytes = Bytes::from(expected.to_bytes().unwrap());
+                let mut buf = ReadBuf::new(bytes.clone());
+                let frame = Frame::deserialize(&mut buf).unwrap();
+                assert!(!buf.0.has_remaining());
+                assert_eq!(frame.reliability, expected.reliability);
+                assert_eq!(frame.frame_idx, expected.frame_idx);
+                assert_eq!(frame.seq, expected.seq);
+                assert_eq!(frame.order_idx, expected.order_idx);
+                assert_eq!(
+                    frame.fragment.map(|f| (f.count, f.index, f.id)),
+                    expected.fragment.map(|f| (f.count, f.index, f.id))
+                );
+                assert_eq!(frame.body, expected.body);
+
+                for len in 0..bytes.len() {
+                    let mut buf = ReadBuf::new(bytes.slice(..len));
+                    assert!(
+                        Frame::deserialize(&mut buf).is_err(),
+                        "truncated at {}",
+                        len
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn body_size_bounds() {
+        let mut max = frame(Reliability::Unreliable, None);
+        max.body = vec![0xab; MAX_BODY_SIZE];
+        let bytes = Bytes::from(max.to_bytes().unwrap());
+        let frame = Frame::deserialize(&mut ReadBuf::new(bytes)).unwrap();
+        assert_eq!(frame.body, max.body);
+
+        max.body.push(0xab);
+        assert!(matches!(
+            max.to_bytes(),
+            Err(MessageError::BodyTooLarge(len)) if len == MAX_BODY_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_body_length() {
+        // unreliable frame declaring an empty body
+        let mut buf = ReadBuf::new(Bytes::from_static(&[0x00, 0x00, 0x00, 0xfe]));
+        assert!(matches!(
+            Frame::deserialize(&mut buf),
+            Err(MessageError::ZeroSize)
+        ));
+
+        // unreliable frame declaring the largest body, with only a few bytes following
+        let mut buf = ReadBuf::new(Bytes::from_static(&[0x00, 0xff, 0xf8, 0xfe, 1, 2, 3]));
+        assert!(matches!(
+            Frame::deserialize(&mut buf),
+            Err(MessageError::BufError(BufError::NotEnoughData))
+        ));
+    }
+}
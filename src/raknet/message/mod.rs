@@ -5,6 +5,7 @@ pub use offline::*;
 pub use online::*;
 
 use super::datatypes::{BufError, ReadBuf, WriteBuf};
+use super::UDP_HEADER_SIZE;
 
 #[derive(Clone, Debug)]
 pub enum MessageError {
@@ -18,6 +19,10 @@ pub enum MessageError {
     UnknownRealibility(u8),
     /// Message was empty, there was nothing to unpack
     ZeroSize,
+    /// Serialized message (size in bytes) does not fit within the MTU
+    ExceedsMTU(usize),
+    /// Frame body (size in bytes) is too large for its length to be encoded
+    BodyTooLarge(usize),
 }
 
 pub trait Message: Sized {
@@ -30,6 +35,19 @@ pub trait Message: Sized {
         self.serialize(&mut buf)?;
         Ok(buf.0.to_vec())
     }
+
+    /// Serializes the message, ensuring the resulting datagram fits within an MTU.
+    ///
+    /// ## Arguments
+    ///
+    /// * `mtu` - Maximum transmission unit, including IP and UDP headers
+    fn to_bytes_with_mtu(&self, mtu: usize) -> Result<Vec<u8>, MessageError> {
+        let bytes = self.to_bytes()?;
+        if bytes.len() > mtu.saturating_sub(UDP_HEADER_SIZE) {
+            return Err(MessageError::ExceedsMTU(bytes.len()));
+        }
+        Ok(bytes)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -1,7 +1,7 @@
 use std::net::SocketAddr;
 
 use crate::raknet::datatypes::{ReadBuf, WriteBuf};
-use crate::raknet::ProtocolVersion;
+use crate::raknet::{ProtocolVersion, UDP_HEADER_SIZE};
 
 use super::{write_header, Message, MessageError, RaknetMessage};
 
@@ -44,6 +44,11 @@ pub struct MessageAlreadyConnected {
     pub server_uuid: i64,
 }
 
+#[derive(Clone, Debug)]
+pub struct MessageNoFreeIncomingConnection {
+    pub server_uuid: i64,
+}
+
 #[derive(Clone, Debug)]
 pub struct MessageIncompatibleProtocolVersion {
     pub server_uuid: i64,
@@ -81,8 +86,9 @@ impl Message for MessageOpenConnectionRequest1 {
         write_header(buf, RaknetMessage::OpenConnectionRequest1)?;
         buf.write_magic()?;
         buf.write_u8(self.raknet_protocol.to_u8())?;
-        let mtu_bytes = vec![0; buf.0.len() + 28];
-        buf.0.extend_from_slice(&mtu_bytes);
+        // the datagram is padded so that its size, with IP and UDP headers, is the MTU
+        let padding = (self.mtu_size as usize).saturating_sub(buf.0.len() + UDP_HEADER_SIZE);
+        buf.0.resize(buf.0.len() + padding, 0);
         Ok(())
     }
 
@@ -91,7 +97,7 @@ impl Message for MessageOpenConnectionRequest1 {
         buf.read_magic()?;
         Ok(Self {
             raknet_protocol: ProtocolVersion::from_u8(buf.read_u8()?),
-            mtu_size: (buf_size + 28)
+            mtu_size: (buf_size + UDP_HEADER_SIZE)
                 .try_into()
                 .map_err(|_| MessageError::MTUInvalidPadding)?,
         })
@@ -176,6 +182,22 @@ impl Message for MessageAlreadyConnected {
     }
 }
 
+impl Message for MessageNoFreeIncomingConnection {
+    fn serialize(&self, buf: &mut WriteBuf) -> Result<(), MessageError> {
+        write_header(buf, RaknetMessage::NoFreeIncomingConnection)?;
+        buf.write_magic()?;
+        buf.write_i64(self.server_uuid)?;
+        Ok(())
+    }
+
+    fn deserialize(buf: &mut ReadBuf) -> Result<Self, MessageError> {
+        buf.read_magic()?;
+        Ok(Self {
+            server_uuid: buf.read_i64()?,
+        })
+    }
+}
+
 impl Message for MessageIncompatibleProtocolVersion {
     fn serialize(&self, buf: &mut WriteBuf) -> Result<(), MessageError> {
         write_header(buf, RaknetMessage::IncompatibleProtocolVersion)?;
@@ -104,7 +104,7 @@ impl Message for MessageConnectionRequestAccepted {
 
     fn deserialize(buf: &mut ReadBuf) -> Result<Self, MessageError> {
         let client_address = buf.read_address()?;
-        buf.0.advance(2); // system index
+        let _ = buf.read_u16()?; // system index
         loop {
             let _ = buf.read_address()?;
             let remaining = buf.0.remaining();
@@ -158,3 +158,97 @@ fn get_bogus_system_address() -> SocketAddr {
     let tmp_ipv4 = Ipv4Addr::new(255, 255, 255, 255);
     SocketAddr::new(IpAddr::V4(tmp_ipv4), 19132)
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    /// Serializes then deserializes a message, checking the header and that it was fully read.
+    fn round_trip<M: Message>(message: &M, message_type: RaknetMessage) -> M {
+        let bytes = message.to_bytes().unwrap();
+        assert_eq!(bytes[0], message_type.to_u8());
+        let mut buf = ReadBuf::new(Bytes::from(bytes).slice(1..));
+        let message = M::deserialize(&mut buf).unwrap();
+        assert!(!buf.0.has_remaining());
+        message
+    }
+
+    /// Deserializes every truncation of a serialized message, which must not panic.
+    ///
+    /// Messages padded with bogus addresses can still be read from some truncations.
+    fn deserialize_truncations<M: Message>(message: &M) {
+        let bytes = Bytes::from(message.to_bytes().unwrap());
+        for len in 1..bytes.len() {
+            let mut buf = ReadBuf::new(bytes.slice(1..len));
+            let _ = M::deserialize(&mut buf);
+        }
+    }
+
+    #[test]
+    fn connected_ping_round_trip() {
+        let message = MessageConnectedPing { timestamp: -42 };
+        let message = round_trip(&message, RaknetMessage::ConnectedPing);
+        assert_eq!(message.timestamp, -42);
+        deserialize_truncations(&message);
+    }
+
+    #[test]
+    fn connected_pong_round_trip() {
+        let message = MessageConnectedPong {
+            ping_timestamp: 1,
+            pong_timestamp: i64::MAX,
+        };
+        let message = round_trip(&message, RaknetMessage::ConnectedPong);
+        assert_eq!(message.ping_timestamp, 1);
+        assert_eq!(message.pong_timestamp, i64::MAX);
+        deserialize_truncations(&message);
+    }
+
+    #[test]
+    fn connection_request_round_trip() {
+        let message = MessageConnectionRequest {
+            client_uuid: 0x0102030405060708,
+            forward_timestamp: 1234,
+            use_encryption: true,
+        };
+        let message = round_trip(&message, RaknetMessage::ConnectionRequest);
+        assert_eq!(message.client_uuid, 0x0102030405060708);
+        assert_eq!(message.forward_timestamp, 1234);
+        assert!(message.use_encryption);
+        deserialize_truncations(&message);
+    }
+
+    #[test]
+    fn connection_request_accepted_round_trip() {
+        for client_address in ["192.168.1.20:54321", "[2001:db8::1]:19132"] {
+            let message = MessageConnectionRequestAccepted {
+                client_address: client_address.parse().unwrap(),
+                request_timestamp: 10,
+                accept_timestamp: 20,
+            };
+            let message = round_trip(&message, RaknetMessage::ConnectionRequestAccepted);
+            assert_eq!(message.client_address, client_address.parse().unwrap());
+            assert_eq!(message.request_timestamp, 10);
+            assert_eq!(message.accept_timestamp, 20);
+            deserialize_truncations(&message);
+        }
+    }
+
+    #[test]
+    fn new_incoming_connection_round_trip() {
+        for server_address in ["10.0.0.1:19132", "[::1]:19133"] {
+            let message = MessageNewIncomingConnection {
+                server_address: server_address.parse().unwrap(),
+                request_timestamp: 30,
+                accept_timestamp: 40,
+            };
+            let message = round_trip(&message, RaknetMessage::NewIncomingConnection);
+            assert_eq!(message.server_address, server_address.parse().unwrap());
+            assert_eq!(message.request_timestamp, 30);
+            assert_eq!(message.accept_timestamp, 40);
+            deserialize_truncations(&message);
+        }
+    }
+}
@@ -2,6 +2,13 @@ pub mod datatypes;
 pub mod frame;
 pub mod message;
 pub mod ping;
+pub mod reassembly;
+
+/// Default MTU, matching a standard Ethernet MTU.
+pub const DEFAULT_MTU: usize = 1492;
+
+/// Size of the IP and UDP headers, counted as part of the MTU.
+pub const UDP_HEADER_SIZE: usize = 28;
 
 /// A Raknet GAME packet header.
 pub const GAME_PACKET_HEADER: u8 = 0xfe;
@@ -1,4 +1,7 @@
 use std::{
+    borrow::Cow,
+    collections::HashMap,
+    net::SocketAddr,
     sync::Arc,
     time::{self, Duration, SystemTime},
 };
@@ -10,13 +13,23 @@ use tokio::{
     time::Instant,
 };
 
-use crate::raknet::{
-    datatypes::ReadBuf,
-    message::{Message, MessageUnconnectedPing, RaknetMessage},
+use crate::{
+    config::{ProxyProtocolMode, ProxyProtocolVersion},
+    proxy_protocol,
+    raknet::{
+        datatypes::ReadBuf,
+        message::{Message, MessageUnconnectedPing, RaknetMessage},
+        DEFAULT_MTU,
+    },
 };
 
 use super::message::MessageUnconnectedPong;
-use ppp::v2 as haproxy;
+
+/// Separator of the fields of a MOTD payload.
+const MOTD_SEPARATOR: char = ';';
+
+/// Delay between two ping attempts.
+const PING_RESEND_INTERVAL: Duration = Duration::from_millis(750);
 
 /// Structured bedrock MOTD representation.
 #[derive(Clone, Debug)]
@@ -63,6 +76,8 @@ pub enum GameMode {
 
 impl Motd {
     /// Encodes a MOTD into a string payload that clients understand.
+    ///
+    /// Separators in text fields are stripped so they can't inject extra fields.
     pub fn encode_payload(&self) -> String {
         let edition = match &self.edition {
             BedrockEdition::PocketEdition => "MCPE",
@@ -74,15 +89,17 @@ impl Motd {
             GameMode::Creative => "Creative",
             GameMode::Custom(str) => str,
         };
+        let edition = strip_separator(edition);
+        let gamemode = strip_separator(gamemode);
         format!(
             "{edition};{};{};{};{};{};{};{};{gamemode};{};{};{};",
-            self.lines[0],
+            strip_separator(&self.lines[0]),
             self.protocol_version,
-            self.version_name,
+            strip_separator(&self.version_name),
             self.player_count,
             self.max_player_count,
             self.server_uuid,
-            self.lines[1],
+            strip_separator(&self.lines[1]),
             (!self.nintendo_limited) as usize,
             self.port_v4,
             self.port_v6
@@ -151,23 +168,206 @@ impl Motd {
     }
 }
 
+/// Removes MOTD field separators from a string.
+fn strip_separator(str: &str) -> Cow<'_, str> {
+    if str.contains(MOTD_SEPARATOR) {
+        Cow::Owned(str.replace(MOTD_SEPARATOR, ""))
+    } else {
+        Cow::Borrowed(str)
+    }
+}
+
+/// Options of a MOTD ping.
+#[derive(Clone, Copy, Debug)]
+pub struct PingOptions {
+    /// Time to wait for a reply. Defaults to 5 seconds.
+    pub timeout: Duration,
+    /// Maximum number of pings to send before the timeout. Unlimited if unset.
+    pub attempts: Option<usize>,
+    /// Version and mode of the PROXY protocol header to send, if required by the server.
+    /// No header is sent by default.
+    pub proxy_protocol: Option<(ProxyProtocolVersion, ProxyProtocolMode)>,
+}
+
+impl Default for PingOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            attempts: None,
+            proxy_protocol: None,
+        }
+    }
+}
+
+/// Client to fetch MOTD information from Bedrock servers.
+///
+/// An ephemeral UDP socket is bound for each ping.
+#[derive(Clone, Debug)]
+pub struct MotdClient {
+    /// Local address to bind sockets to.
+    local_addr: String,
+}
+
+impl MotdClient {
+    /// Creates a client binding sockets to any available port.
+    pub fn new() -> Self {
+        Self::with_local_addr("0.0.0.0:0")
+    }
+
+    /// Creates a client binding sockets to a specific local address.
+    ///
+    /// ## Arguments
+    ///
+    /// * `local_addr` - Local address to bind UDP sockets to
+    pub fn with_local_addr(local_addr: impl Into<String>) -> Self {
+        Self {
+            local_addr: local_addr.into(),
+        }
+    }
+
+    /// Pings a Bedrock server and get MOTD information.
+    ///
+    /// ## Arguments
+    ///
+    /// * `addr` - Address of the remote server
+    /// * `options` - Ping options
+    pub async fn ping<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        options: &PingOptions,
+    ) -> anyhow::Result<Motd> {
+        ping_with_attempts(
+            &self.local_addr,
+            addr,
+            options.proxy_protocol,
+            options.timeout,
+            options.attempts,
+        )
+        .await
+    }
+}
+
+impl Default for MotdClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Pings a Bedrock server and get MOTD information.
 ///
 /// ## Arguments
 ///
 /// * `local_addr` - Local address to bind the UDP socket to
 /// * `addr` - Address of the remote server
-/// * `proxy_protocol` - Whether proxy protocol is required by the server
+/// * `proxy_protocol` - Version and mode of the PROXY protocol header to send, if required by the server
 /// * `timeout` - Timeout duration
 pub async fn ping<A1: ToSocketAddrs, A2: ToSocketAddrs>(
     local_addr: A1,
     addr: A2,
-    proxy_protocol: bool,
+    proxy_protocol: Option<(ProxyProtocolVersion, ProxyProtocolMode)>,
+    timeout: Duration,
+) -> anyhow::Result<Motd> {
+    ping_with_attempts(local_addr, addr, proxy_protocol, timeout, None).await
+}
+
+async fn ping_with_attempts<A1: ToSocketAddrs, A2: ToSocketAddrs>(
+    local_addr: A1,
+    addr: A2,
+    proxy_protocol: Option<(ProxyProtocolVersion, ProxyProtocolMode)>,
     timeout: Duration,
+    max_attempts: Option<usize>,
 ) -> anyhow::Result<Motd> {
     let udp_sock = UdpSocket::bind(local_addr).await?;
     udp_sock.connect(addr).await?;
+    let ping_packet = build_ping_packet(udp_sock.local_addr()?, proxy_protocol)?;
+
+    let udp_sock = Arc::new(udp_sock);
+    let udp_sock_2 = udp_sock.clone();
+    let deadline = Instant::now() + timeout;
 
+    let mut buf = [0u8; DEFAULT_MTU];
+    let len = tokio::select! {
+        res = ping_resender(udp_sock_2, &ping_packet, max_attempts) => {
+            res?;
+            0
+        }
+        res = tokio::time::timeout_at(deadline, udp_sock.recv(&mut buf)) => res??,
+    };
+    decode_pong(&buf[..len])
+}
+
+/// Pings several Bedrock servers over a single UDP socket and get MOTD information.
+///
+/// ## Arguments
+///
+/// * `local_addr` - Local address to bind the UDP socket to
+/// * `targets` - Addresses of the remote servers, with the version and mode of the PROXY
+///               protocol header to send to each, if required
+/// * `timeout` - Timeout duration
+///
+/// ## Returns
+///
+/// MOTD information of the servers that replied in time.
+pub async fn ping_many<A: ToSocketAddrs>(
+    local_addr: A,
+    targets: &[(
+        SocketAddr,
+        Option<(ProxyProtocolVersion, ProxyProtocolMode)>,
+    )],
+    timeout: Duration,
+) -> anyhow::Result<HashMap<SocketAddr, Motd>> {
+    let udp_sock = UdpSocket::bind(local_addr).await?;
+    let local_addr = udp_sock.local_addr()?;
+    let mut packets = HashMap::with_capacity(targets.len());
+    for (addr, proxy_protocol) in targets.iter() {
+        packets.insert(*addr, build_ping_packet(local_addr, *proxy_protocol)?);
+    }
+
+    let mut motds = HashMap::with_capacity(packets.len());
+    let deadline = Instant::now() + timeout;
+    let mut resend_interval = tokio::time::interval(PING_RESEND_INTERVAL);
+    let mut buf = [0u8; DEFAULT_MTU];
+    while motds.len() < packets.len() {
+        tokio::select! {
+            _ = resend_interval.tick() => {
+                for (addr, packet) in packets.iter() {
+                    if motds.contains_key(addr) {
+                        continue;
+                    }
+                    if let Err(err) = udp_sock.send_to(packet, addr).await {
+                        log::trace!("Could not send ping to {}: {:?}", addr, err);
+                    }
+                }
+            }
+            res = tokio::time::timeout_at(deadline, udp_sock.recv_from(&mut buf)) => {
+                let (len, addr) = match res {
+                    Ok(Ok(res)) => res,
+                    Ok(Err(err)) => {
+                        log::trace!("Error receiving ping reply: {:?}", err);
+                        continue;
+                    }
+                    Err(_) => break,
+                };
+                if !packets.contains_key(&addr) {
+                    continue;
+                }
+                match decode_pong(&buf[..len]) {
+                    Ok(motd) => {
+                        motds.insert(addr, motd);
+                    }
+                    Err(err) => log::trace!("Invalid ping reply from {}: {:?}", addr, err),
+                }
+            }
+        }
+    }
+    Ok(motds)
+}
+
+/// Builds an unconnected ping packet, preceded by a PROXY protocol header if required.
+fn build_ping_packet(
+    local_addr: SocketAddr,
+    proxy_protocol: Option<(ProxyProtocolVersion, ProxyProtocolMode)>,
+) -> anyhow::Result<Vec<u8>> {
     let now = SystemTime::now()
         .duration_since(time::UNIX_EPOCH)?
         .as_secs()
@@ -177,14 +377,8 @@ pub async fn ping<A1: ToSocketAddrs, A2: ToSocketAddrs>(
         forward_timestamp: now,
     };
 
-    let ping_packet = if proxy_protocol {
-        let local_addr = udp_sock.local_addr()?;
-        let header = haproxy::Builder::with_addresses(
-            haproxy::Version::Two | haproxy::Command::Proxy,
-            haproxy::Protocol::Datagram,
-            (local_addr, local_addr),
-        )
-        .build()?;
+    let ping_packet = if let Some((version, mode)) = proxy_protocol {
+        let header = proxy_protocol::build_header(version, mode, local_addr, local_addr)?;
 
         let mut buf = header;
         buf.extend(ping.to_bytes()?);
@@ -192,20 +386,11 @@ pub async fn ping<A1: ToSocketAddrs, A2: ToSocketAddrs>(
     } else {
         ping.to_bytes()?
     };
+    Ok(ping_packet)
+}
 
-    let udp_sock = Arc::new(udp_sock);
-    let udp_sock_2 = udp_sock.clone();
-    let deadline = Instant::now() + timeout;
-
-    let mut buf = [0u8; 1492];
-    let len = tokio::select! {
-        res = ping_resender(udp_sock_2, &ping_packet) => {
-            res?;
-            0
-        }
-        res = tokio::time::timeout_at(deadline, udp_sock.recv(&mut buf)) => res??,
-    };
-    let buf = &buf[..len];
+/// Decodes the MOTD information of an unconnected pong packet.
+fn decode_pong(buf: &[u8]) -> anyhow::Result<Motd> {
     let mut buf = ReadBuf::new(Bytes::copy_from_slice(buf));
     let message_type = RaknetMessage::from_u8(buf.read_u8()?);
     if !matches!(message_type, Some(RaknetMessage::UnconnectedPong)) {
@@ -216,12 +401,45 @@ pub async fn ping<A1: ToSocketAddrs, A2: ToSocketAddrs>(
     Ok(motd)
 }
 
-async fn ping_resender(udp_sock: Arc<UdpSocket>, ping_packet: &[u8]) -> anyhow::Result<()> {
+async fn ping_resender(
+    udp_sock: Arc<UdpSocket>,
+    ping_packet: &[u8],
+    max_attempts: Option<usize>,
+) -> anyhow::Result<()> {
     let mut attempts = 0;
     loop {
+        if max_attempts.is_some_and(|max| attempts >= max) {
+            // keep waiting for a reply until the timeout
+            std::future::pending::<()>().await;
+        }
         attempts += 1;
         log::trace!("Ping attempt #{} to {}", attempts, udp_sock.peer_addr()?);
         udp_sock.send(ping_packet).await?;
-        tokio::time::sleep(Duration::from_millis(750)).await;
+        tokio::time::sleep(PING_RESEND_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_packet_uses_proxy_protocol_mode() {
+        let local_addr: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+
+        let packet = build_ping_packet(local_addr, None).unwrap();
+        assert_eq!(packet[0], RaknetMessage::UnconnectedPing.to_u8());
+
+        // address family and transport protocol of a v2 header follow its signature and command
+        let udp = (ProxyProtocolVersion::V2, ProxyProtocolMode::Udp);
+        let packet = build_ping_packet(local_addr, Some(udp)).unwrap();
+        assert_eq!(packet[13], 0x12);
+        let tcp = (ProxyProtocolVersion::V2, ProxyProtocolMode::Tcp);
+        let packet = build_ping_packet(local_addr, Some(tcp)).unwrap();
+        assert_eq!(packet[13], 0x11);
+        assert_eq!(
+            packet[packet.len() - 33],
+            RaknetMessage::UnconnectedPing.to_u8()
+        );
     }
 }
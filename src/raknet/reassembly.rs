@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::frame::FrameFragment;
+
+/// Maximum number of fragments a frame can be split into to be tracked.
+const MAX_FRAGMENT_COUNT: u32 = 1024;
+
+/// Maximum number of split frames tracked at once.
+const MAX_PENDING_FRAMES: usize = 64;
+
+/// Time after which a frame that is still missing fragments is evicted.
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tracks fragments of split frames until all of them have been received.
+///
+/// Only the first fragment of a frame is kept, as it holds the message ID, which is
+/// all that is inspected. The other ones are only marked as received.
+///
+/// Memory is bounded: frames are refused past a total tracked size (bookkeeping included)
+/// or a number of frames, and frames that never complete are evicted after some time.
+pub struct FragmentReassembler {
+    /// Frames being collected, keyed by fragment ID.
+    pending: HashMap<u16, PendingFrame>,
+    /// Total size of the tracked frames, in bytes.
+    size: usize,
+    /// Maximum total size of tracked frames, in bytes.
+    max_size: usize,
+}
+
+/// A split frame that is missing fragments.
+struct PendingFrame {
+    /// Number of fragments the frame is split into.
+    count: u32,
+    /// Bitset of the received fragment indices.
+    received_set: Vec<u64>,
+    /// Number of fragments received.
+    received: u32,
+    /// Body of the first fragment, once received.
+    head: Option<Vec<u8>>,
+    /// Size accounted for the frame, in bytes.
+    size: usize,
+    /// Last time a fragment was received.
+    last_update: Instant,
+}
+
+impl FragmentReassembler {
+    /// Creates an empty reassembler.
+    ///
+    /// ## Arguments
+    ///
+    /// * `max_size` - Maximum total size of tracked frames, in bytes
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            pending: HashMap::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    /// Adds a fragment.
+    ///
+    /// Returns the body of the first fragment of the frame once all its fragments have been received.
+    /// Duplicate fragments (e.g. retransmissions) are ignored.
+    ///
+    /// ## Arguments
+    ///
+    /// * `fragment` - Fragment information of the frame
+    /// * `body` - Body of the frame
+    pub fn push(&mut self, fragment: &FrameFragment, body: Vec<u8>) -> Option<Vec<u8>> {
+        if fragment.count == 0
+            || fragment.count > MAX_FRAGMENT_COUNT
+            || fragment.index >= fragment.count
+        {
+            return None;
+        }
+        let now = Instant::now();
+        self.evict_stale(now);
+
+        if let Some(pending) = self.pending.get(&fragment.id) {
+            if pending.count != fragment.count {
+                // the ID was reused for another frame, the previous one won't complete
+                self.remove(fragment.id);
+            }
+        }
+        if !self.pending.contains_key(&fragment.id) {
+            let pending = PendingFrame::new(fragment.count, now);
+            if self.pending.len() >= MAX_PENDING_FRAMES || self.size + pending.size > self.max_size
+            {
+                return None;
+            }
+            self.size += pending.size;
+            self.pending.insert(fragment.id, pending);
+        }
+
+        let pending = self.pending.get_mut(&fragment.id)?;
+        if !pending.mark_received(fragment.index) {
+            return None;
+        }
+        pending.last_update = now;
+        if fragment.index == 0 {
+            if self.size + body.len() > self.max_size {
+                self.remove(fragment.id);
+                return None;
+            }
+            self.size += body.len();
+            pending.size += body.len();
+            pending.head = Some(body);
+        }
+        if pending.received < pending.count {
+            return None;
+        }
+
+        let pending = self.remove(fragment.id)?;
+        pending.head
+    }
+
+    /// Removes a tracked frame, releasing its size.
+    fn remove(&mut self, id: u16) -> Option<PendingFrame> {
+        let pending = self.pending.remove(&id)?;
+        self.size -= pending.size;
+        Some(pending)
+    }
+
+    /// Evicts frames that haven't received fragments in a while.
+    fn evict_stale(&mut self, now: Instant) {
+        let size = &mut self.size;
+        self.pending.retain(|_, pending| {
+            let stale = now.duration_since(pending.last_update) >= FRAGMENT_TIMEOUT;
+            if stale {
+                *size -= pending.size;
+            }
+            !stale
+        });
+    }
+}
+
+impl PendingFrame {
+    fn new(count: u32, now: Instant) -> Self {
+        let received_set = vec![0u64; (count as usize).div_ceil(64)];
+        let size = std::mem::size_of::<Self>() + received_set.len() * std::mem::size_of::<u64>();
+        Self {
+            count,
+            received_set,
+            received: 0,
+            head: None,
+            size,
+            last_update: now,
+        }
+    }
+
+    /// Marks a fragment as received.
+    ///
+    /// Returns whether it wasn't received before.
+    fn mark_received(&mut self, index: u32) -> bool {
+        let word = &mut self.received_set[index as usize / 64];
+        let bit = 1u64 << (index % 64);
+        if *word & bit != 0 {
+            return false;
+        }
+        *word |= bit;
+        self.received += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(id: u16, index: u32, count: u32) -> FrameFragment {
+        FrameFragment { count, index, id }
+    }
+
+    #[test]
+    fn completes_out_of_order() {
+        let mut reassembler = FragmentReassembler::new(1024 * 1024);
+        assert_eq!(reassembler.push(&fragment(1, 2, 3), vec![3]), None);
+        assert_eq!(reassembler.push(&fragment(1, 0, 3), vec![0x15, 1]), None);
+        assert_eq!(
+            reassembler.push(&fragment(1, 1, 3), vec![2]),
+            Some(vec![0x15, 1])
+        );
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.size, 0);
+    }
+
+    #[test]
+    fn ignores_duplicates() {
+        let mut reassembler = FragmentReassembler::new(1024 * 1024);
+        assert_eq!(reassembler.push(&fragment(1, 0, 2), vec![0x15]), None);
+        assert_eq!(reassembler.push(&fragment(1, 0, 2), vec![0x15]), None);
+        assert_eq!(reassembler.pending[&1].received, 1);
+        assert_eq!(
+            reassembler.push(&fragment(1, 1, 2), vec![0]),
+            Some(vec![0x15])
+        );
+        // a retransmission after completion starts tracking again but doesn't complete
+        assert_eq!(reassembler.push(&fragment(1, 1, 2), vec![0]), None);
+    }
+
+    #[test]
+    fn resets_reused_id() {
+        let mut reassembler = FragmentReassembler::new(1024 * 1024);
+        assert_eq!(reassembler.push(&fragment(1, 0, 3), vec![0xfe]), None);
+        assert_eq!(reassembler.push(&fragment(1, 1, 2), vec![0]), None);
+        assert_eq!(reassembler.pending[&1].count, 2);
+        assert_eq!(
+            reassembler.push(&fragment(1, 0, 2), vec![0x15]),
+            Some(vec![0x15])
+        );
+        assert_eq!(reassembler.size, 0);
+    }
+
+    #[test]
+    fn refuses_over_limit() {
+        let mut reassembler = FragmentReassembler::new(1024 * 1024);
+        assert_eq!(
+            reassembler.push(&fragment(1, 0, MAX_FRAGMENT_COUNT + 1), vec![0]),
+            None
+        );
+        assert_eq!(reassembler.push(&fragment(1, 2, 2), vec![0]), None);
+        assert!(reassembler.pending.is_empty());
+
+        for id in 0..MAX_PENDING_FRAMES as u16 {
+            assert_eq!(reassembler.push(&fragment(id, 1, 2), vec![]), None);
+        }
+        let id = MAX_PENDING_FRAMES as u16;
+        assert_eq!(reassembler.push(&fragment(id, 1, 2), vec![]), None);
+        assert!(!reassembler.pending.contains_key(&id));
+
+        // the bookkeeping of empty fragments counts towards the maximum size
+        let mut reassembler = FragmentReassembler::new(std::mem::size_of::<PendingFrame>() * 2);
+        assert_eq!(reassembler.push(&fragment(1, 1, 2), vec![]), None);
+        assert_eq!(reassembler.push(&fragment(2, 1, 2), vec![]), None);
+        assert_eq!(reassembler.pending.len(), 1);
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of shards to split buckets into, to limit lock contention.
+const SHARD_COUNT: usize = 16;
+
+/// Number of buckets a shard can hold before full buckets get pruned.
+const SHARD_PRUNE_THRESHOLD: usize = 1024;
+
+/// Maximum number of buckets a shard can hold. New IPs are refused past that.
+const SHARD_MAX_BUCKETS: usize = 8 * SHARD_PRUNE_THRESHOLD;
+
+/// Minimum time between two prunes of a shard.
+const SHARD_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Token-bucket rate limiter keyed by source IP.
+///
+/// Each IP gets a bucket holding up to `rate` tokens, refilled at `rate` tokens per second.
+pub struct RateLimiter {
+    /// Buckets, sharded by IP hash.
+    shards: Vec<Mutex<Shard>>,
+    /// Number of times the limit was exceeded.
+    exceeded_count: AtomicU64,
+}
+
+struct Shard {
+    /// Buckets by IP.
+    buckets: HashMap<IpAddr, TokenBucket>,
+    /// Last time full buckets were pruned.
+    last_prune: Instant,
+}
+
+struct TokenBucket {
+    /// Available tokens.
+    tokens: f64,
+    /// Last time tokens were refilled.
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            shards: (0..SHARD_COUNT)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        buckets: HashMap::new(),
+                        last_prune: now,
+                    })
+                })
+                .collect(),
+            exceeded_count: AtomicU64::new(0),
+        }
+    }
+
+    /// Attempts to take a token for an IP.
+    ///
+    /// Returns whether the action is allowed.
+    ///
+    /// ## Arguments
+    ///
+    /// * `ip` - Source IP
+    /// * `rate` - Allowed actions per second
+    pub fn check(&self, ip: IpAddr, rate: u32) -> bool {
+        self.check_at(ip, rate, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, rate: u32, now: Instant) -> bool {
+        let capacity = rate as f64;
+        let mut shard = self.shard(ip).lock().unwrap();
+        if shard.buckets.len() >= SHARD_PRUNE_THRESHOLD
+            && now.duration_since(shard.last_prune) >= SHARD_PRUNE_INTERVAL
+        {
+            // buckets refill entirely in a second, past that they hold no state
+            shard.buckets.retain(|_, bucket| {
+                now.duration_since(bucket.last_refill) < Duration::from_secs(1)
+            });
+            shard.last_prune = now;
+        }
+        if shard.buckets.len() >= SHARD_MAX_BUCKETS && !shard.buckets.contains_key(&ip) {
+            // most likely a flood of spoofed addresses, refuse rather than growing unbounded
+            self.exceeded_count.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let bucket = shard.buckets.entry(ip).or_insert(TokenBucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = f64::min(capacity, bucket.tokens + elapsed * capacity);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            self.exceeded_count.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Number of times the limit was exceeded since the limiter was created.
+    pub fn exceeded_count(&self) -> u64 {
+        self.exceeded_count.load(Ordering::Relaxed)
+    }
+
+    fn shard(&self, ip: IpAddr) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        ip.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARD_COUNT]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn limits_to_rate() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(IP, 3, now));
+        }
+        assert!(!limiter.check_at(IP, 3, now));
+        assert_eq!(limiter.exceeded_count(), 1);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        assert!(limiter.check_at(IP, 2, now));
+        assert!(limiter.check_at(IP, 2, now));
+        assert!(!limiter.check_at(IP, 2, now));
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at(IP, 2, later));
+        assert!(!limiter.check_at(IP, 2, later));
+    }
+
+    #[test]
+    fn prunes_refilled_buckets() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        let ips: Vec<IpAddr> = (0..SHARD_COUNT * SHARD_PRUNE_THRESHOLD * 2)
+            .map(|i| IpAddr::V4(Ipv4Addr::from(i as u32)))
+            .collect();
+        for ip in ips.iter() {
+            limiter.check_at(*ip, 1, now);
+        }
+        let later = now + Duration::from_secs(2);
+        limiter.check_at(IP, 1, later);
+        let shard = limiter.shard(IP).lock().unwrap();
+        assert_eq!(shard.buckets.len(), 1);
+    }
+
+    #[test]
+    fn caps_shard_size() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        let mut refused = 0;
+        for i in 0..(SHARD_COUNT * SHARD_MAX_BUCKETS * 2) as u32 {
+            if !limiter.check_at(IpAddr::V4(Ipv4Addr::from(i)), 1, now) {
+                refused += 1;
+            }
+        }
+        assert!(refused > 0);
+        for shard in limiter.shards.iter() {
+            assert!(shard.lock().unwrap().buckets.len() <= SHARD_MAX_BUCKETS);
+        }
+    }
+}
@@ -1,9 +1,31 @@
-use std::{fs, path::Path, time::SystemTime};
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    time::{Duration, SystemTime},
+};
 
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::config::RootConfig;
 
+/// Current version of the snapshot format.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Magic bytes at the start of gzip compressed data.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// How snapshot files are written.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotFormat {
+    /// Whether to compress the snapshot with gzip.
+    pub compress: bool,
+    /// Whether to pretty-print the JSON, for easier inspection.
+    pub pretty: bool,
+}
+
 /// A snapshot of a [`crate::proxy::RaknetProxy`] state, used
 /// to recover UDP connections after a restart (if it only takes a few seconds).
 ///
@@ -11,6 +33,11 @@ use crate::config::RootConfig;
 /// it does not mean to be a 1:1 representation of the proxy state.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RaknetProxySnapshot {
+    /// Version of the snapshot format.
+    ///
+    /// Snapshots written before it was introduced don't have one, they are version 0.
+    #[serde(default)]
+    pub version: u32,
     /// Time at which the snapshot was taken.
     ///
     /// If too much time has elapsed (i.e. more than a few seconds),
@@ -41,22 +68,84 @@ pub struct RaknetClientSnapshot {
     pub proxy_server_bind: String,
 }
 
+impl RaknetProxySnapshot {
+    /// Checks whether the snapshot is too old to be recovered from.
+    ///
+    /// ## Arguments
+    ///
+    /// * `max_age` - Maximum age of a snapshot that can be recovered from
+    pub fn has_expired(&self, max_age: Duration) -> bool {
+        self.taken_at
+            .elapsed()
+            .map(|elapsed| elapsed >= max_age)
+            .unwrap_or(true)
+    }
+}
+
 /// Writes a [`RaknetProxySnapshot`] into a file.
 ///
+/// The snapshot is written to a temporary file next to it first, then moved
+/// over the target, so the file is never left partially written.
+/// Both the file and the rename are synced to disk before returning.
+///
 /// ## Arguments
 ///
 /// * `path` - File path
+/// * `snapshot` - Snapshot to write
+/// * `format` - How to write the snapshot
 pub fn write_snapshot_file<P: AsRef<Path>>(
     path: P,
     snapshot: &RaknetProxySnapshot,
+    format: &SnapshotFormat,
 ) -> anyhow::Result<()> {
-    let serialized = serde_json::to_string(snapshot)?;
-    fs::write(path, serialized)?;
+    let mut serialized = if format.pretty {
+        serde_json::to_vec_pretty(snapshot)?
+    } else {
+        serde_json::to_vec(snapshot)?
+    };
+    if format.compress {
+        serialized = gzip(&serialized)?;
+    }
+    let path = path.as_ref();
+    let mut tmp_name = path
+        .file_name()
+        .context("snapshot path has no file name")?
+        .to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(&serialized)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+    // the rename itself is only durable once the directory entry is flushed
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::File::open(parent)?.sync_all()?;
+    }
     Ok(())
 }
 
+/// Removes a snapshot file, if it exists.
+///
+/// ## Arguments
+///
+/// * `path` - File path
+pub fn remove_snapshot_file<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
 /// Reads a [`RaknetProxySnapshot`] from a file.
 ///
+/// Compressed snapshots are detected and decompressed transparently.
+///
 /// ## Arguments
 ///
 /// * `path` - File path
@@ -64,7 +153,133 @@ pub fn read_snapshot_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Option<Rakn
     if !path.as_ref().try_exists()? {
         return Ok(None);
     }
-    let contents = fs::read_to_string(&path)?;
-    let deserialized: RaknetProxySnapshot = serde_json::from_str(&contents)?;
+    let mut contents = fs::read(&path)?;
+    if contents.starts_with(&GZIP_MAGIC) {
+        contents = gunzip(&contents)?;
+    }
+    let mut value: Value = serde_json::from_slice(&contents)?;
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > SNAPSHOT_VERSION as u64 {
+        return Err(anyhow::anyhow!(
+            "Unsupported snapshot version {} (latest supported is {})",
+            version,
+            SNAPSHOT_VERSION
+        ));
+    }
+    migrate_snapshot(&mut value, version)?;
+    let deserialized: RaknetProxySnapshot = serde_json::from_value(value)?;
     Ok(Some(deserialized))
 }
+
+/// Upgrades a serialized snapshot to the current format version.
+///
+/// ## Arguments
+///
+/// * `value` - Serialized snapshot
+/// * `version` - Format version of the serialized snapshot
+fn migrate_snapshot(value: &mut Value, version: u64) -> anyhow::Result<()> {
+    let object = value.as_object_mut().context("snapshot is not an object")?;
+    for from_version in version..SNAPSHOT_VERSION as u64 {
+        match from_version {
+            // v1 only introduced the version field
+            0 => {}
+            _ => unreachable!(),
+        }
+        object.insert("version".to_owned(), Value::from(from_version + 1));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "snapshot-compression")]
+fn gzip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(feature = "snapshot-compression")]
+fn gunzip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut decoder = flate2::read::GzDecoder::new(data);
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+#[cfg(not(feature = "snapshot-compression"))]
+fn gzip(_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Err(anyhow::anyhow!(
+        "trakt was built without the `snapshot-compression` feature"
+    ))
+}
+
+#[cfg(not(feature = "snapshot-compression"))]
+fn gunzip(_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Err(anyhow::anyhow!(
+        "Snapshot is compressed but trakt was built without the `snapshot-compression` feature"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_snapshot() -> RaknetProxySnapshot {
+        let config = toml::from_str(
+            r#"
+bind_address = "127.0.0.1:19132"
+proxy_bind = "127.0.0.1:0"
+
+[backend]
+health_check_rate = 60
+motd_refresh_rate = 60
+servers = [{ address = "127.0.0.1:19133" }]
+"#,
+        )
+        .unwrap();
+        RaknetProxySnapshot {
+            version: SNAPSHOT_VERSION,
+            taken_at: SystemTime::now(),
+            config,
+            player_proxy_bind: "127.0.0.1:19132".to_owned(),
+            clients: vec![RaknetClientSnapshot {
+                addr: "127.0.0.1:50000".to_owned(),
+                server_addr: "127.0.0.1:19133".to_owned(),
+                proxy_server_bind: "127.0.0.1:0".to_owned(),
+            }],
+        }
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let dir = std::env::temp_dir().join(format!("trakt-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.json");
+        let snapshot = test_snapshot();
+
+        for pretty in [false, true] {
+            let format = SnapshotFormat {
+                compress: false,
+                pretty,
+            };
+            write_snapshot_file(&path, &snapshot, &format).unwrap();
+            assert!(!dir.join("snapshot.json.tmp").exists());
+
+            let read = read_snapshot_file(&path).unwrap().unwrap();
+            assert_eq!(read.version, SNAPSHOT_VERSION);
+            assert_eq!(read.taken_at, snapshot.taken_at);
+            assert_eq!(read.player_proxy_bind, snapshot.player_proxy_bind);
+            assert_eq!(read.config.bind_address, snapshot.config.bind_address);
+            assert_eq!(read.clients.len(), 1);
+            assert_eq!(read.clients[0].addr, snapshot.clients[0].addr);
+            assert_eq!(read.clients[0].server_addr, snapshot.clients[0].server_addr);
+        }
+
+        remove_snapshot_file(&path).unwrap();
+        assert!(read_snapshot_file(&path).unwrap().is_none());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
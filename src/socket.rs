@@ -0,0 +1,157 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+
+use crate::config::RootConfig;
+
+/// Options applied to the UDP sockets created by the proxy.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UdpSocketOptions {
+    /// Size of the OS receive buffer (`SO_RCVBUF`), in bytes.
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the OS send buffer (`SO_SNDBUF`), in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// Whether to set `SO_REUSEADDR`.
+    pub reuse_address: bool,
+}
+
+impl UdpSocketOptions {
+    /// Reads socket options from config.
+    ///
+    /// ## Arguments
+    ///
+    /// * `config` - Root config
+    pub fn from_config(config: &RootConfig) -> Self {
+        Self {
+            recv_buffer_size: config.udp_recv_buffer_bytes,
+            send_buffer_size: config.udp_send_buffer_bytes,
+            reuse_address: config.udp_reuse_address.unwrap_or(false),
+        }
+    }
+}
+
+/// Binds a UDP socket, applying options before binding.
+///
+/// Every address `addr` resolves to is tried until one can be bound.
+///
+/// ## Arguments
+///
+/// * `addr` - Address to bind to
+/// * `options` - Socket options
+pub async fn bind_udp<A: ToSocketAddrs>(
+    addr: A,
+    options: &UdpSocketOptions,
+) -> io::Result<UdpSocket> {
+    let mut last_err = None;
+    for addr in lookup_host(addr).await? {
+        match bind_udp_addr(addr, options) {
+            Ok(sock) => return Ok(sock),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+fn bind_udp_addr(addr: SocketAddr, options: &UdpSocketOptions) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if options.reuse_address {
+        socket.set_reuse_address(true)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Sets the IP ToS/DSCP byte of outgoing packets of a socket.
+///
+/// ## Arguments
+///
+/// * `sock` - Socket
+/// * `tos` - ToS byte
+pub fn set_tos(sock: &UdpSocket, tos: u8) -> io::Result<()> {
+    SockRef::from(sock).set_tos(tos as u32)
+}
+
+/// Pool of pre-bound UDP sockets for Proxy <-> Server traffic, to reduce bind churn
+/// on connection storms.
+pub struct SocketPool {
+    /// Address the sockets are bound to.
+    bind_address: String,
+    /// Maximum number of idle sockets kept in the pool.
+    capacity: usize,
+    /// Idle sockets.
+    sockets: std::sync::Mutex<Vec<Arc<UdpSocket>>>,
+}
+
+impl SocketPool {
+    /// Creates a pool and fills it with freshly bound sockets.
+    ///
+    /// ## Arguments
+    ///
+    /// * `bind_address` - Address to bind sockets to
+    /// * `capacity` - Number of sockets to pre-allocate and maximum number of idle sockets
+    /// * `options` - Socket options
+    pub async fn new(
+        bind_address: String,
+        capacity: usize,
+        options: &UdpSocketOptions,
+    ) -> io::Result<Self> {
+        let mut sockets = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            sockets.push(Arc::new(bind_udp(bind_address.as_str(), options).await?));
+        }
+        Ok(Self {
+            bind_address,
+            capacity,
+            sockets: std::sync::Mutex::new(sockets),
+        })
+    }
+
+    /// Takes an idle socket out of the pool, if any.
+    ///
+    /// ## Arguments
+    ///
+    /// * `bind_address` - Address the socket must be bound to
+    pub fn checkout(&self, bind_address: &str) -> Option<Arc<UdpSocket>> {
+        if self.bind_address != bind_address {
+            return None;
+        }
+        self.sockets.lock().unwrap().pop()
+    }
+
+    /// Returns a socket to the pool once the connection using it is over.
+    /// Datagrams still queued for the previous connection are discarded.
+    ///
+    /// ## Arguments
+    ///
+    /// * `bind_address` - Address the socket was bound to
+    /// * `sock` - Socket to recycle
+    pub fn checkin(&self, bind_address: &str, sock: Arc<UdpSocket>) {
+        if self.bind_address != bind_address {
+            return;
+        }
+        let mut buf = [0u8; 1];
+        while sock.try_recv_from(&mut buf).is_ok() {}
+        let mut sockets = self.sockets.lock().unwrap();
+        if sockets.len() < self.capacity {
+            sockets.push(sock);
+        }
+    }
+
+    /// Number of idle sockets in the pool.
+    pub fn idle_count(&self) -> usize {
+        self.sockets.lock().unwrap().len()
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+/// Interval at which the number of open files is checked against the limit.
+pub const FD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Gets the current soft limit of open files, when it can be determined.
+#[cfg(target_os = "linux")]
+pub fn soft_fd_limit() -> Option<u64> {
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    line["Max open files".len()..]
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Gets the current soft limit of open files, when it can be determined.
+#[cfg(not(target_os = "linux"))]
+pub fn soft_fd_limit() -> Option<u64> {
+    None
+}
+
+/// Gets the number of files currently opened by the process, when it can be determined.
+#[cfg(target_os = "linux")]
+pub fn open_fd_count() -> Option<usize> {
+    std::fs::read_dir("/proc/self/fd")
+        .ok()
+        .map(|entries| entries.count())
+}
+
+/// Gets the number of files currently opened by the process, when it can be determined.
+#[cfg(not(target_os = "linux"))]
+pub fn open_fd_count() -> Option<usize> {
+    None
+}
+
+/// Checks whether the number of open files is close enough to the limit
+/// that new connections may soon fail.
+///
+/// ## Arguments
+///
+/// * `open` - Number of open files
+/// * `limit` - Maximum number of open files
+pub fn is_near_limit(open: usize, limit: u64) -> bool {
+    // widened so that huge limits can't overflow
+    open as u128 * 10 >= limit as u128 * 9
+}
+
+/// Periodically warns when the number of open files approaches the limit.
+///
+/// ## Arguments
+///
+/// * `limit` - Maximum number of open files
+pub async fn watch_fd_usage(limit: u64) {
+    let mut interval = tokio::time::interval(FD_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let open = match open_fd_count() {
+            Some(open) => open,
+            None => return,
+        };
+        if is_near_limit(open, limit) {
+            log::warn!(
+                "{} files are open out of a limit of {}, new connections may be dropped. Consider raising the limit with --raise-ulimit",
+                open,
+                limit
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn near_limit_from_ninety_percent() {
+        assert!(!is_near_limit(0, 1024));
+        assert!(!is_near_limit(921, 1024));
+        assert!(is_near_limit(922, 1024));
+        assert!(is_near_limit(1024, 1024));
+        assert!(is_near_limit(2000, 1024));
+        assert!(is_near_limit(0, 0));
+    }
+
+    #[test]
+    fn huge_limits_do_not_overflow() {
+        assert!(is_near_limit(usize::MAX, u64::MAX));
+        assert!(!is_near_limit(1024, u64::MAX));
+        assert!(is_near_limit(usize::MAX, u64::MAX / 2));
+    }
+}